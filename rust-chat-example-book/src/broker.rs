@@ -0,0 +1,1059 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tokio::task::JoinHandle;
+
+use crate::commands::{help, Command};
+use crate::history::{Conversation, History, HistoryConfig};
+use crate::message::{ChatMessage, MessageKind, Outgoing, OutputFormat, Rendered};
+use crate::nickname::{self, confusable_skeleton, MAX_LENGTH};
+use crate::offline::{self, Mailboxes};
+use crate::outbox::{outbox, OutboxReceiver, OutboxSender, QueueConfig};
+use crate::pairing::{self, Pairing};
+use crate::presence::{self, Presence};
+use crate::rate_limit::{ClientLimiter, RateLimiter, RateLimits, Traffic};
+use crate::rooms::{self, Rooms};
+use crate::storage::{Record, Store};
+use crate::utils::BoxedResult;
+
+// The broker is the single place that knows about every connected client,
+// no matter if they came in over telnet or ssh.
+// Connectors only turn their own input into `Event`s and hand the broker
+// something that implements `Transport`, so the broker can write back to them.
+
+#[async_trait]
+pub trait Transport: Send + Sync + 'static {
+    // `message` comes without a line ending, every transport adds its own,
+    // and as `format` says: json has to stay one object per line
+    async fn send(&mut self, message: &str, format: OutputFormat) -> BoxedResult<()>;
+    // shown in /who, f.e. "telnet"
+    fn kind(&self) -> &'static str;
+    // ends the connection from the server's side, the connector then sends `Event::Disconnect`
+    async fn close(&mut self);
+    // clients from the same address share a rate limit
+    fn peer_ip(&self) -> Option<IpAddr>;
+}
+
+pub enum Event {
+    NewClient {
+        name: String,
+        transport: Box<dyn Transport>,
+        joined: oneshot::Sender<Join>,
+    },
+    Command {
+        name: String,
+        command: Command,
+    },
+    // a line from the client that could not be understood, `message` tells them why,
+    // it counts against their rate limit like any other line
+    InvalidInput {
+        name: String,
+        message: String,
+    },
+    // used by connectors that need the names themselves, f.e. for tab completion
+    ClientNames {
+        reply: oneshot::Sender<Vec<String>>,
+    },
+    // sent by the connector once the client is gone, be it by /quit, EOF or a failed write
+    Disconnect {
+        name: String,
+    },
+    // a notice from the server to everyone, f.e. the shutdown countdown
+    Broadcast {
+        message: String,
+    },
+    // connectors may still hold senders when the server stops, so the broker is stopped explicitly
+    Shutdown,
+}
+
+// the broker's answer to `Event::NewClient`
+pub enum Join {
+    // `name` is the normalized name the client is known as from now on
+    Accepted { name: String },
+    // the name breaks the nickname rules or is already in use, `reason` tells the user why
+    Rejected { reason: String },
+}
+
+pub struct Client {
+    sender: OutboxSender,
+    // writes everything sent through `sender` to the client's transport
+    writer: JoinHandle<()>,
+    format: OutputFormat,
+    // whoever this client last exchanged a direct message with, for lines without a command
+    conversation_partner: Option<String>,
+    // whoever last sent this client a direct message, for /reply
+    last_sender: Option<String>,
+    pub pairing: Pairing,
+    // clients waiting for this one to accept their /connect
+    pub connect_requests: Vec<String>,
+    pub presence: Presence,
+    // where lines without a command go, unless the client is in a /connect conversation
+    pub current_room: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub rate_limiter: ClientLimiter,
+}
+
+impl Client {
+    // fails only once the client is gone or too slow, which its writer takes care of
+    pub fn deliver(&self, outgoing: &Outgoing) {
+        let _ = self.sender.send(Rendered {
+            text: outgoing.render(self.format),
+            format: self.format,
+        });
+    }
+}
+
+pub type Clients = HashMap<String, Client>;
+
+// messages that never reached slow clients, printed when the server stops
+#[derive(Default)]
+struct QueueMetrics {
+    dropped: u64,
+    disconnected: usize,
+}
+
+impl QueueMetrics {
+    fn record(&mut self, name: &str, client: &Client) {
+        let dropped = client.sender.dropped();
+        if dropped > 0 {
+            println!("{name} missed {dropped} messages because they read too slowly.");
+        }
+        self.dropped += dropped;
+        if client.sender.overflowed() {
+            self.disconnected += 1;
+        }
+    }
+}
+
+const NAME_SUGGESTIONS: usize = 3;
+// how long the goodbye may take to reach the clients when the server stops
+const SHUTDOWN_WRITE_DEADLINE: Duration = Duration::from_secs(2);
+
+const NO_CONVERSATION: &str = "You are not in a conversation yet, start one with \"/message <name> <message>\" or \"/join <room>\".";
+
+// `queue` limits how many messages may wait for each client,
+// `rate_limits` how many each client may send and `history` how many are kept for late comers,
+// `store` is where history and rooms are kept across restarts
+pub fn start_broker(
+    queue: QueueConfig,
+    rate_limits: RateLimits,
+    history: HistoryConfig,
+    store: Box<dyn Store>,
+) -> (UnboundedSender<Event>, tokio::task::JoinHandle<()>) {
+    let (broker_sender, broker_receiver) = unbounded_channel();
+    let broker = tokio::spawn(broker_loop(
+        broker_receiver,
+        queue,
+        rate_limits,
+        history,
+        store,
+    ));
+    (broker_sender, broker)
+}
+
+async fn broker_loop(
+    mut events: UnboundedReceiver<Event>,
+    queue: QueueConfig,
+    rate_limits: RateLimits,
+    history: HistoryConfig,
+    store: Box<dyn Store>,
+) {
+    let mut clients: Clients = HashMap::new();
+    let mut rooms: Rooms = HashMap::new();
+    let mut history = History::new(history, store);
+    let mut mailboxes = Mailboxes::default();
+    for record in history.restore().await {
+        match record {
+            Record::Topic { room, topic } => rooms::restore_topic(&mut rooms, room, Some(topic)),
+            Record::TopicCleared { room } => rooms::restore_topic(&mut rooms, room, None),
+            record => mailboxes.restore(record),
+        }
+    }
+    // drops what is beyond the history size since the last run
+    compact(&mut history, &rooms, &mailboxes);
+    let mut rate_limiter = RateLimiter::new(rate_limits);
+    let mut metrics = QueueMetrics::default();
+
+    loop {
+        let event = match events.recv().await {
+            Some(event) => event,
+            None => break,
+        };
+
+        match event {
+            Event::NewClient {
+                name,
+                transport,
+                joined,
+            } => {
+                let join = add_client(
+                    &mut clients,
+                    &name,
+                    transport,
+                    queue,
+                    rate_limiter.limits(),
+                    &history,
+                );
+                if let Join::Accepted { name } = &join {
+                    offline::welcome(&mut mailboxes, &clients, &mut history, name);
+                }
+                let _ = joined.send(join);
+            }
+            Event::Command { name, command } => {
+                let command = resolve_names(&clients, &rooms, &mailboxes, command);
+                let traffic = traffic(&clients, &name, &command);
+                if !rate_limiter.allow(&mut clients, &name, traffic) {
+                    continue;
+                }
+                if let Some(client) = clients.get_mut(&name) {
+                    client.presence.last_active = Instant::now();
+                }
+                match command {
+                    Command::Message {
+                        to_names, message, ..
+                    } => {
+                        // "#games, bob: Hi!" goes to the room and to bob
+                        let (room_names, to_names): (Vec<String>, Vec<String>) =
+                            to_names.into_iter().partition(|to| to.starts_with('#'));
+                        for room in &room_names {
+                            let room = rooms::room_name(room);
+                            rooms::say(&rooms, &clients, &mut history, &name, room, &message);
+                        }
+                        if !to_names.is_empty() {
+                            send_messages(
+                                &mut clients,
+                                &mut history,
+                                &mut mailboxes,
+                                &name,
+                                to_names,
+                                &message,
+                            );
+                        }
+                    }
+                    Command::Text(message) => match current_conversation(&clients, &name) {
+                        Some(Conversation::Room(room)) => {
+                            rooms::say(&rooms, &clients, &mut history, &name, &room, &message)
+                        }
+                        Some(Conversation::Direct(partner)) => send_messages(
+                            &mut clients,
+                            &mut history,
+                            &mut mailboxes,
+                            &name,
+                            vec![partner],
+                            &message,
+                        ),
+                        None => send_notice(&clients, &name, NO_CONVERSATION),
+                    },
+                    Command::Reply(message) => {
+                        let sender = clients
+                            .get(&name)
+                            .and_then(|client| client.last_sender.clone());
+                        match sender {
+                            Some(sender) => send_messages(
+                                &mut clients,
+                                &mut history,
+                                &mut mailboxes,
+                                &name,
+                                vec![sender],
+                                &message,
+                            ),
+                            None => send_notice(&clients, &name, "Nobody sent you a message yet."),
+                        }
+                    }
+                    Command::Connect(with) => pairing::connect(&mut clients, &name, &with),
+                    Command::Accept(requester) => pairing::accept(&mut clients, &name, requester),
+                    Command::Decline(requester) => pairing::decline(&mut clients, &name, requester),
+                    Command::Disconnect => pairing::disconnect(&mut clients, &name, false),
+                    Command::Join(room) => {
+                        rooms::join(&mut rooms, &mut clients, &history, &name, &room)
+                    }
+                    Command::Leave(room) => rooms::leave(&mut rooms, &mut clients, &name, room),
+                    Command::Rooms => rooms::list(&rooms, &clients, &name),
+                    Command::Topic(text) => {
+                        rooms::topic(&mut rooms, &clients, &mut history, &name, text)
+                    }
+                    Command::History(count) => {
+                        // without a room or conversation, the messages to everyone
+                        let conversation = current_conversation(&clients, &name)
+                            .unwrap_or(Conversation::Room("all".to_string()));
+                        let count = count.unwrap_or(history.config().size);
+                        if !history.replay(&clients, &name, &conversation, count) {
+                            send_notice(&clients, &name, "There are no earlier messages.");
+                        }
+                    }
+                    Command::Clients => list_clients(&clients, &name),
+                    Command::Who => presence::who(&clients, &name),
+                    Command::Away(reason) => presence::away(&mut clients, &name, reason),
+                    Command::Back => presence::back(&mut clients, &name),
+                    Command::Help => send_notice(&clients, &name, help()),
+                    Command::Format(format) => {
+                        if let Some(client) = clients.get_mut(&name) {
+                            client.format = format;
+                        }
+                        send_notice(&clients, &name, "The output format was changed.");
+                    }
+                    // never sent, `handle_input` has the connector close the connection instead
+                    Command::Quit => (),
+                }
+            }
+            Event::InvalidInput { name, message } => {
+                if rate_limiter.allow(&mut clients, &name, Traffic::Direct) {
+                    send_notice(&clients, &name, message);
+                }
+            }
+            Event::ClientNames { reply } => {
+                let mut names: Vec<String> = clients.keys().cloned().collect();
+                names.sort();
+                let _ = reply.send(names);
+            }
+            Event::Disconnect { name } => {
+                if remove_client(&mut clients, &mut rooms, &name, &mut metrics) {
+                    println!("{} left.", name);
+                }
+            }
+            Event::Broadcast { message } => {
+                for name in clients.keys() {
+                    send_notice(&clients, name, message.as_str());
+                }
+            }
+            Event::Shutdown => break,
+        }
+
+        if history.needs_compaction() {
+            compact(&mut history, &rooms, &mailboxes);
+        }
+    }
+    let shutdown_notice = Outgoing::notice("Admin is shutting down the server...");
+    for (name, client) in &clients {
+        client.deliver(&shutdown_notice);
+        metrics.record(name, client);
+    }
+    if metrics.dropped > 0 {
+        println!(
+            "Dropped {} messages for slow clients and disconnected {} of them.",
+            metrics.dropped, metrics.disconnected
+        );
+    }
+
+    // dropping the senders lets every writer stop once it wrote what is left,
+    // the connectors close the connections after the broker is done
+    let writers: Vec<JoinHandle<()>> = clients.into_values().map(|client| client.writer).collect();
+    let flushed = tokio::time::timeout(SHUTDOWN_WRITE_DEADLINE, async {
+        for writer in writers {
+            let _ = writer.await;
+        }
+    });
+    if flushed.await.is_err() {
+        eprintln!("Not every client received the shutdown notice in time.");
+    }
+    history.close().await;
+}
+
+// spawns `function`, printing its error instead of leaving it in the JoinHandle
+pub fn spawn_and_log_error<F>(function: F) -> JoinHandle<()>
+where
+    F: Future<Output = BoxedResult<()>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = function.await {
+            eprintln!("{}", e)
+        }
+    })
+}
+
+// registers a client with the broker, connectors have to ask for another name on rejection
+pub async fn join(
+    broker_sender: &UnboundedSender<Event>,
+    name: &str,
+    transport: Box<dyn Transport>,
+) -> BoxedResult<Join> {
+    let (joined, join) = oneshot::channel();
+    broker_sender.send(Event::NewClient {
+        name: name.to_string(),
+        transport,
+        joined,
+    })?;
+    Ok(join.await?)
+}
+
+fn add_client(
+    clients: &mut Clients,
+    name: &str,
+    mut transport: Box<dyn Transport>,
+    queue: QueueConfig,
+    rate_limits: &RateLimits,
+    history: &History,
+) -> Join {
+    let name = match nickname::validate(name) {
+        Ok(name) => name,
+        Err(error) => {
+            return Join::Rejected {
+                reason: error.to_string(),
+            }
+        }
+    };
+    if let Some(taken) = look_alike(clients, &name) {
+        return Join::Rejected {
+            reason: name_taken(clients, &name, taken),
+        };
+    }
+
+    let kind = transport.kind();
+    let ip = transport.peer_ip();
+    let (client_sender, client_receiver) = outbox(queue);
+    let writer_name = name.clone();
+    let writer = spawn_and_log_error(async move {
+        receive_messages_on_loop(&writer_name, &client_receiver, transport.as_mut()).await
+    });
+    clients.insert(
+        name.clone(),
+        Client {
+            sender: client_sender,
+            writer,
+            format: OutputFormat::Text,
+            conversation_partner: None,
+            last_sender: None,
+            pairing: Pairing::Alone,
+            connect_requests: Vec::new(),
+            presence: Presence::new(kind),
+            current_room: None,
+            ip,
+            rate_limiter: ClientLimiter::new(rate_limits),
+        },
+    );
+    presence::announce(clients, &name, &format!("{name} joined the chat."));
+    let all = Conversation::Room("all".to_string());
+    history.replay(clients, &name, &all, history.config().replay);
+    Join::Accepted { name }
+}
+
+// "lol: same" or "12:30 works?" only look like the book's syntax,
+// so such lines are messages only if every name is a client, a room or "all"
+fn resolve_names(
+    clients: &Clients,
+    rooms: &Rooms,
+    mailboxes: &Mailboxes,
+    command: Command,
+) -> Command {
+    match command {
+        Command::Message {
+            to_names,
+            line: Some(line),
+            ..
+        } if !to_names.iter().all(|to| {
+            to == "all"
+                || (to.starts_with('#') && rooms.contains_key(rooms::room_name(to)))
+                || clients.contains_key(to)
+                || mailboxes.is_known(to)
+        }) =>
+        {
+            Command::Text(line)
+        }
+        command => command,
+    }
+}
+
+// messages to everyone or to a room reach many clients, so they have their own, stricter limit
+fn traffic(clients: &Clients, name: &str, command: &Command) -> Traffic {
+    let broadcast = match command {
+        Command::Message { to_names, .. } => {
+            to_names.iter().any(|to| to == "all" || to.starts_with('#'))
+        }
+        Command::Text(_) => matches!(
+            current_conversation(clients, name),
+            Some(Conversation::Room(_))
+        ),
+        _ => false,
+    };
+    match broadcast {
+        true => Traffic::Broadcast,
+        false => Traffic::Direct,
+    }
+}
+
+// returns false once the client asked to quit,
+// the connector then closes the connection and sends `Event::Disconnect`
+pub fn handle_input(
+    broker_sender: &UnboundedSender<Event>,
+    name: &str,
+    line: &str,
+) -> BoxedResult<bool> {
+    let event = match Command::parse(line) {
+        Ok(Command::Quit) => return Ok(false),
+        Ok(command) => Event::Command {
+            name: name.to_string(),
+            command,
+        },
+        Err(error) => Event::InvalidInput {
+            name: name.to_string(),
+            message: error.to_string(),
+        },
+    };
+    broker_sender.send(event)?;
+    Ok(true)
+}
+
+pub async fn client_names(broker_sender: &UnboundedSender<Event>) -> Vec<String> {
+    let (reply, names) = oneshot::channel();
+    if broker_sender.send(Event::ClientNames { reply }).is_err() {
+        return Vec::new();
+    }
+    names.await.unwrap_or_default()
+}
+
+// history, rooms and mailboxes share one store, so everything they need is kept together
+fn compact(history: &mut History, rooms: &Rooms, mailboxes: &Mailboxes) {
+    let mut records = rooms::records(rooms);
+    records.extend(mailboxes.records());
+    history.compact(records);
+}
+
+// where lines without a command go: a /connect conversation wins over the current room,
+// and that over whoever was messaged last
+fn current_conversation(clients: &Clients, name: &str) -> Option<Conversation> {
+    let client = clients.get(name)?;
+    if let Some(partner) = client.pairing.partner() {
+        return Some(Conversation::Direct(partner.clone()));
+    }
+    match (&client.current_room, &client.conversation_partner) {
+        (Some(room), _) => Some(Conversation::Room(room.clone())),
+        (None, Some(partner)) => Some(Conversation::Direct(partner.clone())),
+        (None, None) => None,
+    }
+}
+
+// direct messages to clients that are offline wait for them, if they were here before
+fn send_messages(
+    clients: &mut Clients,
+    history: &mut History,
+    mailboxes: &mut Mailboxes,
+    from: &str,
+    to: Vec<String>,
+    msg: &str,
+) {
+    let all_command = "all".to_string();
+
+    if to.contains(&all_command) {
+        let message = ChatMessage::new(from, MessageKind::Room { room: all_command }, msg);
+        history.record_room("all", &message);
+        let message = Outgoing::Chat(message);
+        for (name, client) in clients {
+            if name != from {
+                client.deliver(&message);
+            }
+        }
+    } else {
+        let chat_message = ChatMessage::new(from, MessageKind::Direct, msg);
+        let message = Outgoing::Chat(chat_message.clone());
+        for name in &to {
+            match clients.get_mut(name) {
+                Some(client) => {
+                    client.deliver(&message);
+                    client.conversation_partner = Some(from.to_string());
+                    client.last_sender = Some(from.to_string());
+                    presence::notify_if_away(clients, from, name);
+                }
+                None if mailboxes.is_known(name) => {
+                    if !offline::leave_message(mailboxes, clients, history, name, &chat_message) {
+                        continue;
+                    }
+                }
+                None => {
+                    send_notice(clients, from, format!("There is nobody called {name}."));
+                    continue;
+                }
+            }
+            history.record_direct(from, name, &chat_message);
+        }
+        // with several receivers it's not clear who the conversation is with
+        if let [receiver] = &to[..] {
+            if clients.contains_key(receiver) {
+                if let Some(sender) = clients.get_mut(from) {
+                    sender.conversation_partner = Some(receiver.clone());
+                }
+            }
+        }
+    }
+}
+
+// frees the name and forgets everything the other clients remember about it,
+// dropping the sender ends the client's receive loop
+fn remove_client(
+    clients: &mut Clients,
+    rooms: &mut Rooms,
+    name: &str,
+    metrics: &mut QueueMetrics,
+) -> bool {
+    pairing::disconnect(clients, name, true);
+    match clients.remove(name) {
+        Some(client) => metrics.record(name, &client),
+        None => return false,
+    }
+    rooms::leave_all(rooms, name);
+
+    for other in clients.values_mut() {
+        if other.conversation_partner.as_deref() == Some(name) {
+            other.conversation_partner = None;
+        }
+        if other.last_sender.as_deref() == Some(name) {
+            other.last_sender = None;
+        }
+    }
+    presence::announce(clients, name, &format!("{name} left the chat."));
+    true
+}
+
+// the name of a client that `name` could be mistaken for, if there is one
+fn look_alike<'a>(clients: &'a Clients, name: &str) -> Option<&'a String> {
+    let skeleton = confusable_skeleton(name);
+    clients
+        .keys()
+        .find(|client| confusable_skeleton(client) == skeleton)
+}
+
+fn name_taken(clients: &Clients, name: &str, taken: &str) -> String {
+    let suggestions: Vec<String> = (2..)
+        .map(|number: u32| {
+            // long names are cut so the number still fits
+            let number = number.to_string();
+            let base: String = name.chars().take(MAX_LENGTH - number.len()).collect();
+            base + &number
+        })
+        .filter(|suggestion| look_alike(clients, suggestion).is_none())
+        .take(NAME_SUGGESTIONS)
+        .collect();
+    let taken = match taken == name {
+        true => format!("The name {name} is already taken"),
+        false => format!("The name {name} looks too much like {taken}"),
+    };
+    format!("{taken}, how about {}?", suggestions.join(", "))
+}
+
+fn list_clients(clients: &Clients, name: &str) {
+    let mut names: Vec<&str> = clients.keys().map(|name| name.as_str()).collect();
+    names.sort();
+    let list = format!(
+        "Following clients are available to be messaged: {}\nYou are connected as {name}",
+        names.join(", ")
+    );
+    send_notice(clients, name, list);
+}
+
+pub fn send_notice(clients: &Clients, name: &str, text: impl Into<String>) {
+    if let Some(client) = clients.get(name) {
+        client.deliver(&Outgoing::notice(text));
+    }
+}
+
+// a client that reads slower than messages come in may block `send` for a long time,
+// so the writer also gives up as soon as the queue overflows
+async fn receive_messages_on_loop(
+    name: &str,
+    client_receiver: &OutboxReceiver,
+    transport: &mut dyn Transport,
+) -> BoxedResult<()> {
+    let result = loop {
+        let message = tokio::select! {
+            message = client_receiver.recv() => message,
+            _ = client_receiver.overflowed() => break Err(anyhow!(
+                "Disconnected {name:?}, they couldn't keep up with their messages."
+            )),
+        };
+        let message = match message {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        tokio::select! {
+            sent = transport.send(&message.text, message.format) => if let Err(e) = sent {
+                break Err(e);
+            },
+            _ = client_receiver.overflowed() => break Err(anyhow!(
+                "Disconnected {name:?}, they couldn't keep up with their messages."
+            )),
+        }
+    };
+    transport.close().await;
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::outbox::OverflowPolicy;
+    use crate::rate_limit::Limit;
+    use crate::storage::MemoryStore;
+
+    // keeps everything the broker writes, so tests can look at it
+    #[derive(Clone, Default)]
+    struct TestTransport {
+        sent: Arc<Mutex<Vec<String>>>,
+        ip: Option<IpAddr>,
+    }
+
+    impl TestTransport {
+        fn sent(&self) -> Vec<String> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Transport for TestTransport {
+        async fn send(&mut self, message: &str, _format: OutputFormat) -> BoxedResult<()> {
+            self.sent.lock().unwrap().push(message.to_string());
+            Ok(())
+        }
+
+        fn kind(&self) -> &'static str {
+            "test"
+        }
+
+        async fn close(&mut self) {}
+
+        fn peer_ip(&self) -> Option<IpAddr> {
+            self.ip
+        }
+    }
+
+    const QUEUE: QueueConfig = QueueConfig {
+        capacity: 100,
+        policy: OverflowPolicy::DropOldest,
+    };
+
+    const LIMIT: Limit = Limit {
+        burst: 10,
+        per_second: 1.0,
+    };
+
+    const RATE_LIMITS: RateLimits = RateLimits {
+        direct: LIMIT,
+        broadcast: LIMIT,
+        per_ip: LIMIT,
+        mute_after: 3,
+        mute_for: Duration::from_secs(30),
+    };
+
+    const HISTORY: HistoryConfig = HistoryConfig {
+        size: 10,
+        replay: 10,
+    };
+
+    fn history() -> History {
+        History::new(HISTORY, Box::new(MemoryStore::default()))
+    }
+
+    // a running broker, driven with events the way the connectors do it
+    struct TestBroker {
+        sender: UnboundedSender<Event>,
+        broker: JoinHandle<()>,
+    }
+
+    impl TestBroker {
+        fn start(rate_limits: RateLimits) -> Self {
+            let store = Box::new(MemoryStore::default());
+            let (sender, broker) = start_broker(QUEUE, rate_limits, HISTORY, store);
+            TestBroker { sender, broker }
+        }
+
+        // returns the transport, which has everything `name` got once the broker stopped
+        async fn join(&self, name: &str) -> TestTransport {
+            let transport = TestTransport::default();
+            join(&self.sender, name, Box::new(transport.clone()))
+                .await
+                .unwrap();
+            transport
+        }
+
+        fn input(&self, name: &str, line: &str) {
+            handle_input(&self.sender, name, line).unwrap();
+        }
+
+        fn send(&self, event: Event) {
+            self.sender.send(event).unwrap();
+        }
+
+        // waits until everything before was handled and written
+        async fn stop(self) {
+            self.send(Event::Shutdown);
+            self.broker.await.unwrap();
+        }
+    }
+
+    // clients that joined without a broker, for tests of the modules working on `Clients`
+    pub(crate) struct TestClients {
+        pub clients: Clients,
+        transports: HashMap<String, TestTransport>,
+    }
+
+    impl TestClients {
+        pub(crate) fn new(names: &[&str]) -> Self {
+            let mut clients = Clients::new();
+            let mut transports = HashMap::new();
+            for name in names {
+                let transport = TestTransport::default();
+                assert!(matches!(
+                    join_as(&mut clients, name, transport.clone()),
+                    Join::Accepted { .. }
+                ));
+                transports.insert(name.to_string(), transport);
+            }
+            TestClients {
+                clients,
+                transports,
+            }
+        }
+
+        pub(crate) fn leave(&mut self, name: &str) {
+            let mut metrics = QueueMetrics::default();
+            remove_client(&mut self.clients, &mut Rooms::new(), name, &mut metrics);
+        }
+
+        // everything each client got, once their writers are done
+        pub(crate) async fn sent(self) -> HashMap<String, Vec<String>> {
+            for (_, client) in self.clients {
+                drop(client.sender);
+                client.writer.await.unwrap();
+            }
+            self.transports
+                .into_iter()
+                .map(|(name, transport)| (name, transport.sent()))
+                .collect()
+        }
+    }
+
+    fn join_as(clients: &mut Clients, name: &str, transport: TestTransport) -> Join {
+        add_client(
+            clients,
+            name,
+            Box::new(transport),
+            QUEUE,
+            &RATE_LIMITS,
+            &history(),
+        )
+    }
+
+    fn accepted(clients: &mut Clients, name: &str) -> String {
+        match join_as(clients, name, TestTransport::default()) {
+            Join::Accepted { name } => name,
+            Join::Rejected { reason } => panic!("{name} was rejected: {reason}"),
+        }
+    }
+
+    fn rejected(clients: &mut Clients, name: &str) -> String {
+        match join_as(clients, name, TestTransport::default()) {
+            Join::Accepted { .. } => panic!("{name} was accepted"),
+            Join::Rejected { reason } => reason,
+        }
+    }
+
+    #[tokio::test]
+    async fn taken_name() {
+        let mut clients = Clients::new();
+        accepted(&mut clients, "bob");
+        assert_eq!(
+            rejected(&mut clients, "bob"),
+            "The name bob is already taken, how about bob2, bob3, bob4?"
+        );
+        assert_eq!(clients.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn look_alike_name() {
+        let mut clients = Clients::new();
+        accepted(&mut clients, "bob");
+        assert_eq!(
+            rejected(&mut clients, "Bob"),
+            "The name Bob looks too much like bob, how about Bob2, Bob3, Bob4?"
+        );
+        accepted(&mut clients, "mia");
+        assert_eq!(
+            rejected(&mut clients, "rnia"),
+            "The name rnia looks too much like mia, how about rnia2, rnia3, rnia4?"
+        );
+    }
+
+    #[tokio::test]
+    async fn suggestions_skip_names_in_use() {
+        let mut clients = Clients::new();
+        accepted(&mut clients, "bob");
+        accepted(&mut clients, "bob3");
+        assert_eq!(
+            rejected(&mut clients, "bob"),
+            "The name bob is already taken, how about bob2, bob4, bob5?"
+        );
+    }
+
+    #[tokio::test]
+    async fn suggestions_are_cut_to_max_length() {
+        let mut clients = Clients::new();
+        let long = "abcdefghijklmnopqrst";
+        assert_eq!(long.len(), MAX_LENGTH);
+        accepted(&mut clients, long);
+        assert_eq!(
+            rejected(&mut clients, long),
+            format!(
+                "The name {long} is already taken, how about \
+                 abcdefghijklmnopqrs2, abcdefghijklmnopqrs3, abcdefghijklmnopqrs4?"
+            )
+        );
+        // the suggestions have to be accepted themselves
+        accepted(&mut clients, "abcdefghijklmnopqrs2");
+    }
+
+    #[tokio::test]
+    async fn retry_after_rejection() {
+        let mut clients = Clients::new();
+        let bob = TestTransport::default();
+        assert!(matches!(
+            join_as(&mut clients, "bob", bob.clone()),
+            Join::Accepted { .. }
+        ));
+        rejected(&mut clients, "bob");
+        assert_eq!(accepted(&mut clients, "bob2"), "bob2");
+
+        // bob's writer runs on its own task
+        for _ in 0..100 {
+            if !bob.sent.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(*bob.sent.lock().unwrap(), ["bob2 joined the chat."]);
+    }
+
+    // tokens don't come back, so every test sees exactly `burst` lines go through
+    fn strict_limits(direct: u32, broadcast: u32, per_ip: u32) -> RateLimits {
+        let limit = |burst| Limit {
+            burst,
+            per_second: 0.0,
+        };
+        RateLimits {
+            direct: limit(direct),
+            broadcast: limit(broadcast),
+            per_ip: limit(per_ip),
+            mute_after: 100,
+            mute_for: Duration::from_secs(30),
+        }
+    }
+
+    // runs a broker with `bob` in it until `events` are handled, returns what bob got
+    async fn bob_gets(rate_limits: RateLimits, events: Vec<Event>) -> Vec<String> {
+        let broker = TestBroker::start(rate_limits);
+        let bob = broker.join("bob").await;
+        for event in events {
+            broker.send(event);
+        }
+        broker.stop().await;
+        bob.sent()
+    }
+
+    fn command(command: Command) -> Event {
+        Event::Command {
+            name: "bob".to_string(),
+            command,
+        }
+    }
+
+    const TOO_FAST: &str = "You are sending too fast, that message was dropped.";
+    const TOO_OFTEN: &str =
+        "You are messaging everyone or a room too often, that message was dropped.";
+
+    #[tokio::test]
+    async fn room_messages_are_broadcasts() {
+        let sent = bob_gets(
+            strict_limits(10, 1, 10),
+            vec![
+                command(Command::Join("games".to_string())),
+                command(Command::Text("one".to_string())),
+                command(Command::Text("two".to_string())),
+                command(Command::Message {
+                    to_names: vec!["#games".to_string()],
+                    message: "three".to_string(),
+                    line: None,
+                }),
+            ],
+        )
+        .await;
+        assert_eq!(sent.iter().filter(|line| *line == TOO_OFTEN).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn invalid_input_is_rate_limited() {
+        let error = match Command::parse("/nonsense") {
+            Ok(_) => panic!("/nonsense was understood"),
+            Err(error) => error.to_string(),
+        };
+        let invalid = || Event::InvalidInput {
+            name: "bob".to_string(),
+            message: error.clone(),
+        };
+        let sent = bob_gets(
+            strict_limits(2, 10, 10),
+            vec![invalid(), invalid(), invalid()],
+        )
+        .await;
+        assert_eq!(sent.iter().filter(|line| **line == error).count(), 2);
+        assert_eq!(sent.iter().filter(|line| *line == TOO_FAST).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_lines_take_no_tokens_from_the_address() {
+        let limits = strict_limits(10, 1, 2);
+        let mut rate_limiter = RateLimiter::new(limits);
+        let mut clients = Clients::new();
+        let transport = TestTransport {
+            ip: Some(IpAddr::from([127, 0, 0, 1])),
+            ..TestTransport::default()
+        };
+        add_client(
+            &mut clients,
+            "bob",
+            Box::new(transport),
+            QUEUE,
+            &limits,
+            &history(),
+        );
+
+        assert!(rate_limiter.allow(&mut clients, "bob", Traffic::Broadcast));
+        assert!(!rate_limiter.allow(&mut clients, "bob", Traffic::Broadcast));
+        // the address still has the token the dropped broadcast didn't use
+        assert!(rate_limiter.allow(&mut clients, "bob", Traffic::Direct));
+        assert!(!rate_limiter.allow(&mut clients, "bob", Traffic::Direct));
+    }
+
+    #[tokio::test]
+    async fn lines_that_only_look_addressed_are_text() {
+        let broker = TestBroker::start(RATE_LIMITS);
+        let alice = broker.join("alice").await;
+        broker.join("bob").await;
+        for name in ["alice", "bob"] {
+            broker.input(name, "/join games");
+        }
+        for line in [
+            "lol: same",
+            "12:30 works?",
+            "alice: hi",
+            "#games, alice: both",
+        ] {
+            broker.input("bob", line);
+        }
+        broker.stop().await;
+
+        let sent = alice.sent();
+        let got = |ending: &str| sent.iter().filter(|line| line.ends_with(ending)).count();
+        assert_eq!(got("#games bob: lol: same"), 1);
+        assert_eq!(got("#games bob: 12:30 works?"), 1);
+        assert_eq!(got("bob -> you: hi"), 1);
+        assert_eq!(got("#games bob: both"), 1);
+        assert_eq!(got("bob -> you: both"), 1);
+    }
+}
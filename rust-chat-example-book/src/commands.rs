@@ -0,0 +1,358 @@
+use std::fmt;
+
+use crate::message::OutputFormat;
+
+// Everything a client can type, no matter which transport it uses.
+// Besides the commands below, the syntax from the book still works:
+//      "other_user_1, other_user_2: Hello world!"
+// and anything else goes to the current room or to whoever the client talked to last.
+// Only the broker knows who is around, so it decides if "lol: same" is meant for someone called lol.
+
+pub enum Command {
+    Message {
+        to_names: Vec<String>,
+        message: String,
+        // the whole line if it was written in the book's syntax,
+        // it is sent as `Text` instead unless every name is someone the broker knows
+        line: Option<String>,
+    },
+    // a line without a command, for the current room or conversation partner
+    Text(String),
+    // for whoever sent the last direct message
+    Reply(String),
+    Connect(String),
+    Accept(Option<String>),
+    Decline(Option<String>),
+    Disconnect,
+    Join(String),
+    // the current room if no room is given
+    Leave(Option<String>),
+    Rooms,
+    // shows the topic of the current room, or sets it, "-" clears it
+    Topic(Option<String>),
+    // how many messages, everything that was kept if not given
+    History(Option<usize>),
+    Clients,
+    Who,
+    // with an optional reason
+    Away(Option<String>),
+    Back,
+    Help,
+    Format(OutputFormat),
+    Quit,
+}
+
+pub enum CommandError {
+    EmptyInput,
+    UnknownCommand(String),
+    MissingReceiver,
+    MissingMessage,
+    MissingRoom,
+    NotANumber(String),
+    UnknownFormat,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::EmptyInput => write!(f, "There is nothing to send."),
+            CommandError::UnknownCommand(command) => write!(
+                f,
+                "There is no command {command}. Type /help to see all commands."
+            ),
+            CommandError::MissingReceiver => {
+                write!(f, "Input must include the receiver name, then message.")
+            }
+            CommandError::MissingMessage => write!(f, "Input must include a message."),
+            CommandError::MissingRoom => write!(f, "Input must include the room name."),
+            CommandError::NotANumber(text) => write!(f, "{text} is not a number."),
+            CommandError::UnknownFormat => write!(f, "The format has to be text or json."),
+        }
+    }
+}
+
+struct CommandInfo {
+    name: &'static str,
+    arguments: &'static str,
+    description: &'static str,
+    // gets everything after the command name
+    parse: fn(&str) -> Result<Command, CommandError>,
+}
+
+const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "/message",
+        arguments: "<name>[,<name>...] <message>",
+        description: "send a message, use \"all\" as name to message everyone or #room for a room",
+        parse: parse_message,
+    },
+    CommandInfo {
+        name: "/reply",
+        arguments: "<message>",
+        description: "answer whoever sent you the last direct message",
+        parse: parse_reply,
+    },
+    CommandInfo {
+        name: "/connect",
+        arguments: "<name>",
+        description: "ask someone for a conversation only between the two of you",
+        parse: |arguments| match arguments {
+            "" => Err(CommandError::MissingReceiver),
+            name => Ok(Command::Connect(name.to_string())),
+        },
+    },
+    CommandInfo {
+        name: "/accept",
+        arguments: "[name]",
+        description: "accept a /connect request",
+        parse: |arguments| Ok(Command::Accept(optional_argument(arguments))),
+    },
+    CommandInfo {
+        name: "/decline",
+        arguments: "[name]",
+        description: "decline a /connect request",
+        parse: |arguments| Ok(Command::Decline(optional_argument(arguments))),
+    },
+    CommandInfo {
+        name: "/disconnect",
+        arguments: "",
+        description: "end your /connect conversation or stop waiting for one",
+        parse: |_| Ok(Command::Disconnect),
+    },
+    CommandInfo {
+        name: "/join",
+        arguments: "<room>",
+        description: "enter a room, it is opened if it doesn't exist yet",
+        parse: |arguments| match arguments {
+            "" => Err(CommandError::MissingRoom),
+            room => Ok(Command::Join(room.to_string())),
+        },
+    },
+    CommandInfo {
+        name: "/leave",
+        arguments: "[room]",
+        description: "leave a room, the current one by default",
+        parse: |arguments| Ok(Command::Leave(optional_argument(arguments))),
+    },
+    CommandInfo {
+        name: "/rooms",
+        arguments: "",
+        description: "list all rooms and how many are in them",
+        parse: |_| Ok(Command::Rooms),
+    },
+    CommandInfo {
+        name: "/topic",
+        arguments: "[topic | -]",
+        description: "show the topic of your current room, change it, or clear it with -",
+        parse: |arguments| Ok(Command::Topic(optional_argument(arguments))),
+    },
+    CommandInfo {
+        name: "/history",
+        arguments: "[count]",
+        description: "show earlier messages of your current room or conversation",
+        parse: parse_history,
+    },
+    CommandInfo {
+        name: "/clients",
+        arguments: "",
+        description: "list everyone who is online",
+        parse: |_| Ok(Command::Clients),
+    },
+    CommandInfo {
+        name: "/who",
+        arguments: "",
+        description: "show who is online, how they are connected and how long they are idle",
+        parse: |_| Ok(Command::Who),
+    },
+    CommandInfo {
+        name: "/away",
+        arguments: "[reason]",
+        description: "let others know you are not around",
+        parse: |arguments| Ok(Command::Away(optional_argument(arguments))),
+    },
+    CommandInfo {
+        name: "/back",
+        arguments: "",
+        description: "you are around again",
+        parse: |_| Ok(Command::Back),
+    },
+    CommandInfo {
+        name: "/help",
+        arguments: "",
+        description: "show this help",
+        parse: |_| Ok(Command::Help),
+    },
+    CommandInfo {
+        name: "/format",
+        arguments: "<text|json>",
+        description: "receive messages as text or as one json object per line",
+        parse: parse_format,
+    },
+    CommandInfo {
+        name: "/quit",
+        arguments: "",
+        description: "leave the chat",
+        parse: |_| Ok(Command::Quit),
+    },
+];
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, CommandError> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Err(CommandError::EmptyInput);
+        }
+
+        if !line.starts_with('/') {
+            let text = Command::Text(line.to_string());
+            return match line.split_once(':') {
+                // "Note:" on its own is text as well
+                Some((to_names, message)) if is_name_list(to_names) => {
+                    Ok(message_command(to_names, message, Some(line)).unwrap_or(text))
+                }
+                _ => Ok(text),
+            };
+        }
+
+        let (name, arguments) = match line.split_once(' ') {
+            Some((name, arguments)) => (name, arguments.trim()),
+            None => (line, ""),
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.parse)(arguments),
+            None => Err(CommandError::UnknownCommand(name.to_string())),
+        }
+    }
+}
+
+pub fn help() -> String {
+    let mut help = String::from("Available commands:");
+    for command in COMMANDS {
+        let usage = format!("{} {}", command.name, command.arguments);
+        help.push_str(&format!(
+            "\n  {:<40} {}",
+            usage.trim_end(),
+            command.description
+        ));
+    }
+    help.push_str("\nYou can also write \"name_1, name_2: message\",");
+    help.push_str("\nor just the message for your current room or whoever you talked to last.");
+    help
+}
+
+fn parse_message(arguments: &str) -> Result<Command, CommandError> {
+    match arguments.split_once(' ') {
+        Some((to_names, message)) => message_command(to_names, message, None),
+        None if arguments.is_empty() => Err(CommandError::MissingReceiver),
+        None => Err(CommandError::MissingMessage),
+    }
+}
+
+fn optional_argument(arguments: &str) -> Option<String> {
+    match arguments {
+        "" => None,
+        name => Some(name.to_string()),
+    }
+}
+
+fn parse_reply(arguments: &str) -> Result<Command, CommandError> {
+    match arguments {
+        "" => Err(CommandError::MissingMessage),
+        message => Ok(Command::Reply(message.to_string())),
+    }
+}
+
+fn parse_history(arguments: &str) -> Result<Command, CommandError> {
+    match arguments {
+        "" => Ok(Command::History(None)),
+        count => match count.parse() {
+            Ok(count) => Ok(Command::History(Some(count))),
+            Err(_) => Err(CommandError::NotANumber(count.to_string())),
+        },
+    }
+}
+
+fn parse_format(arguments: &str) -> Result<Command, CommandError> {
+    match arguments {
+        "text" => Ok(Command::Format(OutputFormat::Text)),
+        "json" => Ok(Command::Format(OutputFormat::Json)),
+        _ => Err(CommandError::UnknownFormat),
+    }
+}
+
+// "bob, alice: Hi!" is meant for bob and alice, "Note to self: ..." is just text
+fn is_name_list(to_names: &str) -> bool {
+    to_names
+        .split(',')
+        .map(|name| name.trim())
+        .all(|name| !name.is_empty() && !name.contains(char::is_whitespace))
+}
+
+fn message_command(
+    to_names: &str,
+    message: &str,
+    line: Option<&str>,
+) -> Result<Command, CommandError> {
+    let to_names: Vec<String> = to_names
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let message = message.trim().to_string();
+
+    if to_names.is_empty() {
+        return Err(CommandError::MissingReceiver);
+    }
+    if message.is_empty() {
+        return Err(CommandError::MissingMessage);
+    }
+    Ok(Command::Message {
+        to_names,
+        message,
+        line: line.map(str::to_string),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addressed(line: &str) -> Option<(Vec<String>, String)> {
+        match Command::parse(line) {
+            Ok(Command::Message {
+                to_names,
+                message,
+                line: Some(_),
+            }) => Some((to_names, message)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn book_syntax() {
+        assert_eq!(
+            addressed("bob, alice: Hi there"),
+            Some((
+                vec!["bob".to_string(), "alice".to_string()],
+                "Hi there".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn colons_in_text() {
+        for line in ["Note to self: buy milk", "Note:", "see: "] {
+            assert!(matches!(Command::parse(line), Ok(Command::Text(text)) if text == line.trim()));
+        }
+        // looks like a name, the broker decides
+        assert!(addressed("lol: same").is_some());
+    }
+
+    #[test]
+    fn message_command_keeps_no_line() {
+        assert!(matches!(
+            Command::parse("/message bob hi"),
+            Ok(Command::Message { line: None, .. })
+        ));
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::broker::{send_notice, Clients};
+use crate::message::{ChatMessage, Outgoing};
+use crate::storage::{Record, Store, StoreThread};
+
+// The last messages of every room (including "all") and of every pair of clients
+// that messaged each other directly, so someone who comes in late can catch up.
+// Rooms get theirs replayed when a client enters them, direct messages on /history,
+// to whoever has the name now, see offline.rs.
+// Room history stays when everyone left, so a room that is opened again picks up where it was.
+// Everything recorded also goes to the `Store`, so it is still there after a restart.
+
+#[derive(Clone, Copy)]
+pub struct HistoryConfig {
+    // messages kept per room or pair
+    pub size: usize,
+    // messages replayed when a client enters a room
+    pub replay: usize,
+}
+
+// what /history and lines without a command refer to
+pub enum Conversation {
+    Room(String),
+    // with the other client
+    Direct(String),
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Key {
+    Room(String),
+    // the two names sorted, so both clients share one history
+    Direct(String, String),
+}
+
+impl Key {
+    fn direct(name: &str, other: &str) -> Self {
+        match name < other {
+            true => Key::Direct(name.to_string(), other.to_string()),
+            false => Key::Direct(other.to_string(), name.to_string()),
+        }
+    }
+}
+
+pub struct History {
+    config: HistoryConfig,
+    messages: HashMap<Key, VecDeque<ChatMessage>>,
+    store: StoreThread,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig, store: Box<dyn Store>) -> Self {
+        History {
+            config,
+            messages: HashMap::new(),
+            store: StoreThread::spawn(store),
+        }
+    }
+
+    // takes back the messages from the store and returns the records that belong to others
+    pub async fn restore(&mut self) -> Vec<Record> {
+        let records = match self.store.load().await {
+            Ok(records) => records,
+            Err(e) => {
+                eprintln!("Could not load the stored messages, starting without them: {e}");
+                return Vec::new();
+            }
+        };
+        let mut others = Vec::new();
+        for record in records {
+            match record {
+                Record::RoomMessage { room, message } => {
+                    self.remember(Key::Room(room), message);
+                }
+                Record::DirectMessage { to, message } => {
+                    self.remember(Key::direct(&message.from, &to), message);
+                }
+                record => others.push(record),
+            }
+        }
+        others
+    }
+
+    pub fn persist(&mut self, record: Record) {
+        self.store.append(record);
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.store.needs_compaction()
+    }
+
+    // keeps what is still in the ring buffers, plus `others`, and drops everything else
+    pub fn compact(&mut self, others: Vec<Record>) {
+        let mut records = Vec::new();
+        for (key, messages) in &self.messages {
+            for message in messages {
+                records.push(match key {
+                    Key::Room(room) => Record::RoomMessage {
+                        room: room.clone(),
+                        message: message.clone(),
+                    },
+                    Key::Direct(first, second) => Record::DirectMessage {
+                        to: match *first == message.from {
+                            true => second.clone(),
+                            false => first.clone(),
+                        },
+                        message: message.clone(),
+                    },
+                });
+            }
+        }
+        records.extend(others);
+        self.store.compact(records);
+    }
+
+    // waits until everything is stored
+    pub async fn close(self) {
+        self.store.close().await;
+    }
+
+    pub fn config(&self) -> &HistoryConfig {
+        &self.config
+    }
+
+    pub fn record_room(&mut self, room: &str, message: &ChatMessage) {
+        if self.remember(Key::Room(room.to_string()), message.clone()) {
+            self.persist(Record::RoomMessage {
+                room: room.to_string(),
+                message: message.clone(),
+            });
+        }
+    }
+
+    pub fn record_direct(&mut self, from: &str, to: &str, message: &ChatMessage) {
+        if self.remember(Key::direct(from, to), message.clone()) {
+            self.persist(Record::DirectMessage {
+                to: to.to_string(),
+                message: message.clone(),
+            });
+        }
+    }
+
+    // false if no history is kept at all
+    fn remember(&mut self, key: Key, message: ChatMessage) -> bool {
+        if self.config.size == 0 {
+            return false;
+        }
+        let messages = self.messages.entry(key).or_default();
+        if messages.len() >= self.config.size {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+        true
+    }
+
+    // sends `name` up to `count` of the latest messages, oldest first,
+    // returns false if there were none
+    pub fn replay(
+        &self,
+        clients: &Clients,
+        name: &str,
+        conversation: &Conversation,
+        count: usize,
+    ) -> bool {
+        let (key, title) = match conversation {
+            Conversation::Room(room) => (Key::Room(room.clone()), format!("in #{room}")),
+            Conversation::Direct(other) => (Key::direct(name, other), format!("with {other}")),
+        };
+        let (client, messages) = match (clients.get(name), self.messages.get(&key)) {
+            (Some(client), Some(messages)) if count > 0 && !messages.is_empty() => {
+                (client, messages)
+            }
+            _ => return false,
+        };
+
+        let skipped = messages.len().saturating_sub(count);
+        send_notice(
+            clients,
+            name,
+            format!("The last {} messages {title}:", messages.len() - skipped),
+        );
+        for message in messages.iter().skip(skipped) {
+            client.deliver(&Outgoing::History(message.clone()));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageKind;
+    use crate::storage::MemoryStore;
+
+    const CONFIG: HistoryConfig = HistoryConfig { size: 2, replay: 2 };
+
+    fn texts(history: &History, key: Key) -> Vec<String> {
+        history.messages[&key]
+            .iter()
+            .map(|message| message.text.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn restore_round_trip() {
+        let store = MemoryStore::default();
+        let mut history = History::new(CONFIG, Box::new(store.clone()));
+        let room = MessageKind::Room {
+            room: "games".to_string(),
+        };
+        for text in ["one", "two", "three"] {
+            history.record_room("games", &ChatMessage::new("alice", room.clone(), text));
+        }
+        let direct = ChatMessage::new("bob", MessageKind::Direct, "hi");
+        history.record_direct("bob", "alice", &direct);
+        history.persist(Record::KnownUser {
+            name: "alice".to_string(),
+        });
+        history.close().await;
+
+        let mut restored = History::new(CONFIG, Box::new(store));
+        let others = restored.restore().await;
+        assert!(matches!(&others[..], [Record::KnownUser { name }] if name == "alice"));
+        // only as many as the history keeps
+        assert_eq!(
+            texts(&restored, Key::Room("games".to_string())),
+            ["two", "three"]
+        );
+        assert_eq!(texts(&restored, Key::direct("alice", "bob")), ["hi"]);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::line_editor::LineEditor;
+
+// Bytes from the network arrive in chunks that don't care about lines or characters:
+// a client may send one key press at a time, or a multi-byte character split over two packets.
+// The buffers here collect the chunks and only hand out complete lines.
+
+// a line that is still not finished after this many bytes is handed out anyway,
+// the line editor takes no more characters than this
+pub const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Clone, Default)]
+pub struct LineBuffer {
+    bytes: Vec<u8>,
+}
+
+impl LineBuffer {
+    pub fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.bytes.extend_from_slice(data);
+
+        let mut lines = Vec::new();
+        while let Some(end) = self.bytes.iter().position(|byte| *byte == b'\n') {
+            let mut line: Vec<u8> = self.bytes.drain(..=end).collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            lines.push(String::from_utf8_lossy(&line).to_string());
+        }
+
+        if self.bytes.len() >= MAX_LINE_LENGTH {
+            // never cut a character in half, its missing bytes are still on the way
+            let valid = match std::str::from_utf8(&self.bytes) {
+                Ok(_) => self.bytes.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(_) => self.bytes.len(),
+            };
+            let line: Vec<u8> = self.bytes.drain(..valid).collect();
+            lines.push(String::from_utf8_lossy(&line).to_string());
+        }
+
+        lines
+    }
+}
+
+// what a channel sends depends on whether the client asked for a pty:
+// without one we get whole lines (f.e. from ssh_driver), with one every key press on its own
+#[derive(Clone)]
+pub enum ChannelInput {
+    Lines(LineBuffer),
+    Editor(LineEditor),
+}
+
+impl Default for ChannelInput {
+    fn default() -> Self {
+        ChannelInput::Lines(LineBuffer::default())
+    }
+}
+
+pub struct Input {
+    pub lines: Vec<String>,
+    pub echo: Vec<u8>,
+}
+
+impl ChannelInput {
+    // `names` is used for tab completion, see `LineEditor::feed`
+    pub fn push(&mut self, data: &[u8], names: &[String]) -> Input {
+        match self {
+            ChannelInput::Lines(buffer) => Input {
+                lines: buffer.push(data),
+                echo: Vec::new(),
+            },
+            ChannelInput::Editor(editor) => {
+                let edited = editor.feed(data, names);
+                Input {
+                    lines: edited.lines,
+                    echo: edited.echo,
+                }
+            }
+        }
+    }
+
+    pub fn is_editor(&self) -> bool {
+        matches!(self, ChannelInput::Editor(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_lines() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(buffer.push(b"one\ntwo\r\nthr"), ["one", "two"]);
+        assert_eq!(buffer.push(b"ee\n"), ["three"]);
+    }
+
+    #[test]
+    fn character_split_over_two_pushes() {
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.push(b"a\xc3").is_empty());
+        assert_eq!(buffer.push(b"\xa4\n"), ["aä"]);
+    }
+
+    #[test]
+    fn crlf_split_over_two_pushes() {
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.push(b"hi\r").is_empty());
+        assert_eq!(buffer.push(b"\nyo\r\n"), ["hi", "yo"]);
+    }
+
+    #[test]
+    fn long_line_is_cut_between_characters() {
+        let mut buffer = LineBuffer::default();
+        let mut data = vec![b'a'; MAX_LINE_LENGTH - 1];
+        // the first byte of "ä" makes the line long enough to be cut
+        data.push(0xc3);
+        assert_eq!(buffer.push(&data), ["a".repeat(MAX_LINE_LENGTH - 1)]);
+        assert_eq!(buffer.push(b"\xa4\n"), ["ä"]);
+    }
+}
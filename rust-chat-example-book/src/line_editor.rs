@@ -0,0 +1,570 @@
+// Telnet clients in character mode and ssh clients with a pty send every key on its own,
+// including backspaces and arrow keys as control bytes or escape sequences.
+// LineEditor keeps the line the user is typing, applies those keys to it and
+// returns what has to be echoed so the user's terminal shows the edited line.
+
+use crate::input_buffer::MAX_LINE_LENGTH;
+
+const ESC: u8 = 0x1b;
+const BELL: u8 = 0x07;
+const HISTORY_LENGTH: usize = 100;
+// longer escape sequences are not something we understand anyway
+const MAX_ESCAPE_LENGTH: usize = 16;
+
+#[derive(Clone, Copy)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    Tab,
+    // Ctrl-U
+    DeleteToStart,
+    // Ctrl-W
+    DeleteWord,
+    // Ctrl-C
+    Cancel,
+}
+
+#[derive(Clone, Default)]
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    // index into the history while browsing it with up/down,
+    // and the line that was typed before browsing started
+    browsing: Option<usize>,
+    draft: Vec<char>,
+    escape: Option<Vec<u8>>,
+    utf8: Vec<u8>,
+    last_was_cr: bool,
+}
+
+#[derive(Default)]
+pub struct Edited {
+    // has to be written back to the client
+    pub echo: Vec<u8>,
+    // lines the user finished with enter
+    pub lines: Vec<String>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor::default()
+    }
+
+    // `names` is used for tab completion, it is only needed when `bytes` contains a tab
+    pub fn feed(&mut self, bytes: &[u8], names: &[String]) -> Edited {
+        let mut edited = Edited::default();
+
+        for &byte in bytes {
+            if let Some(key) = self.decode(byte) {
+                self.press(key, names, &mut edited);
+            }
+        }
+
+        edited
+    }
+
+    fn decode(&mut self, byte: u8) -> Option<Key> {
+        let last_was_cr = self.last_was_cr;
+        self.last_was_cr = byte == b'\r';
+
+        if let Some(sequence) = &mut self.escape {
+            sequence.push(byte);
+            let key = match sequence[..] {
+                [b'[', .., end] if (0x40..=0x7e).contains(&end) => csi_key(&sequence[1..]),
+                [b'[', ..] if sequence.len() < MAX_ESCAPE_LENGTH => return None,
+                [b'O'] => return None,
+                [b'O', key] => ss3_key(key),
+                // alt + key or something we don't know
+                _ => None,
+            };
+            self.escape = None;
+            return key;
+        }
+
+        if !self.utf8.is_empty() || byte >= 0x80 {
+            return self.decode_utf8(byte);
+        }
+
+        match byte {
+            b'\r' => Some(Key::Enter),
+            // the \n of \r\n was handled with the \r already
+            b'\n' if last_was_cr => None,
+            b'\n' => Some(Key::Enter),
+            0x08 | 0x7f => Some(Key::Backspace),
+            b'\t' => Some(Key::Tab),
+            0x01 => Some(Key::Home),
+            0x02 => Some(Key::Left),
+            0x03 => Some(Key::Cancel),
+            0x05 => Some(Key::End),
+            0x06 => Some(Key::Right),
+            0x0e => Some(Key::Down),
+            0x10 => Some(Key::Up),
+            0x15 => Some(Key::DeleteToStart),
+            0x17 => Some(Key::DeleteWord),
+            ESC => {
+                self.escape = Some(Vec::new());
+                None
+            }
+            byte if byte < 0x20 => None,
+            byte => Some(Key::Char(byte as char)),
+        }
+    }
+
+    // multi-byte characters may arrive one byte at a time
+    fn decode_utf8(&mut self, byte: u8) -> Option<Key> {
+        self.utf8.push(byte);
+
+        let expected_length = match self.utf8[0] {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => {
+                self.utf8.clear();
+                return None;
+            }
+        };
+        if self.utf8.len() < expected_length {
+            return None;
+        }
+
+        let character = std::str::from_utf8(&self.utf8)
+            .ok()
+            .and_then(|string| string.chars().next());
+        self.utf8.clear();
+        character.map(Key::Char)
+    }
+
+    fn press(&mut self, key: Key, names: &[String], edited: &mut Edited) {
+        let old_cursor = self.cursor;
+        let echo = &mut edited.echo;
+
+        match key {
+            // a client that never presses enter must not fill the server's memory
+            Key::Char(_) if self.line.len() >= MAX_LINE_LENGTH => echo.push(BELL),
+            Key::Char(character) => {
+                self.line.insert(self.cursor, character);
+                self.cursor += 1;
+                self.redraw_from(old_cursor, old_cursor, echo);
+            }
+            Key::Enter => {
+                let line: String = self.line.drain(..).collect();
+                self.cursor = 0;
+                self.browsing = None;
+                if !line.trim().is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                    if self.history.len() > HISTORY_LENGTH {
+                        self.history.remove(0);
+                    }
+                }
+                echo.extend(b"\r\n");
+                edited.lines.push(line);
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                self.redraw_from(self.cursor, old_cursor, echo);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw_from(self.cursor, old_cursor, echo);
+            }
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                move_left(1, echo);
+            }
+            Key::Right if self.cursor < self.line.len() => {
+                self.cursor += 1;
+                move_right(1, echo);
+            }
+            Key::Home => {
+                self.cursor = 0;
+                move_left(old_cursor, echo);
+            }
+            Key::End => {
+                self.cursor = self.line.len();
+                move_right(self.cursor - old_cursor, echo);
+            }
+            Key::Up => {
+                let index = match self.browsing {
+                    None if !self.history.is_empty() => {
+                        self.draft = self.line.clone();
+                        self.history.len() - 1
+                    }
+                    Some(index) if index > 0 => index - 1,
+                    _ => return,
+                };
+                self.browsing = Some(index);
+                let line = self.history[index].chars().collect();
+                self.replace_line(line, echo);
+            }
+            Key::Down => {
+                let line = match self.browsing {
+                    Some(index) if index + 1 < self.history.len() => {
+                        self.browsing = Some(index + 1);
+                        self.history[index + 1].chars().collect()
+                    }
+                    Some(_) => {
+                        self.browsing = None;
+                        std::mem::take(&mut self.draft)
+                    }
+                    None => return,
+                };
+                self.replace_line(line, echo);
+            }
+            Key::Tab => self.complete(names, echo),
+            Key::DeleteToStart => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+                self.redraw_from(0, old_cursor, echo);
+            }
+            Key::DeleteWord => {
+                let mut start = self.cursor;
+                while start > 0 && self.line[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.line[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.line.drain(start..self.cursor);
+                self.cursor = start;
+                self.redraw_from(start, old_cursor, echo);
+            }
+            Key::Cancel => {
+                self.line.clear();
+                self.cursor = 0;
+                self.browsing = None;
+                echo.extend(b"^C\r\n");
+            }
+            // backspace at the start of the line and the like
+            _ => (),
+        }
+    }
+
+    // completes the name in front of the cursor,
+    // or lists all names that could be meant if that is not clear
+    fn complete(&mut self, names: &[String], echo: &mut Vec<u8>) {
+        let start = self.line[..self.cursor]
+            .iter()
+            .rposition(|character| *character == ' ' || *character == ',')
+            .map_or(0, |position| position + 1);
+        let prefix: String = self.line[start..self.cursor].iter().collect();
+
+        let candidates: Vec<&String> = names
+            .iter()
+            .filter(|name| name.starts_with(&prefix))
+            .collect();
+        let common_prefix = match candidates.split_first() {
+            None => {
+                echo.push(BELL);
+                return;
+            }
+            Some((first, rest)) => rest.iter().fold(first.to_string(), |common, name| {
+                common
+                    .chars()
+                    .zip(name.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a)
+                    .collect()
+            }),
+        };
+
+        let completion: Vec<char> = common_prefix.chars().skip(prefix.chars().count()).collect();
+        if self.line.len() + completion.len() > MAX_LINE_LENGTH {
+            echo.push(BELL);
+        } else if !completion.is_empty() {
+            let old_cursor = self.cursor;
+            let length = completion.len();
+            self.line.splice(self.cursor..self.cursor, completion);
+            self.cursor += length;
+            self.redraw_from(old_cursor, old_cursor, echo);
+        } else if candidates.len() > 1 {
+            let candidates: Vec<&str> = candidates.iter().map(|name| name.as_str()).collect();
+            echo.extend(format!("\r\n{}\r\n", candidates.join("  ")).as_bytes());
+            self.redraw_from(0, 0, echo);
+        }
+    }
+
+    fn replace_line(&mut self, line: Vec<char>, echo: &mut Vec<u8>) {
+        let old_cursor = self.cursor;
+        self.line = line;
+        self.cursor = self.line.len();
+        self.redraw_from(0, old_cursor, echo);
+    }
+
+    // the terminal's cursor is at `old_cursor`, everything from `from` on may have changed
+    fn redraw_from(&self, from: usize, old_cursor: usize, echo: &mut Vec<u8>) {
+        move_left(old_cursor.saturating_sub(from), echo);
+        let rest: String = self.line[from..].iter().collect();
+        echo.extend(rest.as_bytes());
+        if from < old_cursor || self.cursor < self.line.len() || rest.is_empty() {
+            // clear what is left of the old, longer line
+            echo.extend(b"\x1b[K");
+        }
+        move_left(self.line.len() - self.cursor, echo);
+    }
+}
+
+// ESC [ <parameters> <final byte>
+fn csi_key(sequence: &[u8]) -> Option<Key> {
+    match sequence {
+        b"A" => Some(Key::Up),
+        b"B" => Some(Key::Down),
+        b"C" => Some(Key::Right),
+        b"D" => Some(Key::Left),
+        b"H" | b"1~" | b"7~" => Some(Key::Home),
+        b"F" | b"4~" | b"8~" => Some(Key::End),
+        b"3~" => Some(Key::Delete),
+        _ => None,
+    }
+}
+
+// ESC O <key>, sent instead of ESC [ by terminals in application mode
+fn ss3_key(key: u8) -> Option<Key> {
+    match key {
+        b'A' => Some(Key::Up),
+        b'B' => Some(Key::Down),
+        b'C' => Some(Key::Right),
+        b'D' => Some(Key::Left),
+        b'H' => Some(Key::Home),
+        b'F' => Some(Key::End),
+        _ => None,
+    }
+}
+
+fn move_left(columns: usize, echo: &mut Vec<u8>) {
+    if columns > 0 {
+        echo.extend(format!("\x1b[{columns}D").as_bytes());
+    }
+}
+
+fn move_right(columns: usize, echo: &mut Vec<u8>) {
+    if columns > 0 {
+        echo.extend(format!("\x1b[{columns}C").as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL_LINE: [u8; MAX_LINE_LENGTH] = [b'a'; MAX_LINE_LENGTH];
+    const FULL_LINE_ECHO: &str = match std::str::from_utf8(&FULL_LINE) {
+        Ok(echo) => echo,
+        Err(_) => panic!("the full line is ascii"),
+    };
+
+    struct Case {
+        name: &'static str,
+        names: &'static [&'static str],
+        // every chunk is fed on its own, with what has to be echoed for it
+        steps: &'static [(&'static [u8], &'static str)],
+        lines: &'static [&'static str],
+    }
+
+    const CASES: &[Case] = &[
+        Case {
+            name: "typing and enter",
+            names: &[],
+            steps: &[(b"hi", "hi"), (b"\r", "\r\n")],
+            lines: &["hi"],
+        },
+        Case {
+            name: "cr lf is one line",
+            names: &[],
+            steps: &[(b"a\r\nb\n", "a\r\nb\r\n")],
+            lines: &["a", "b"],
+        },
+        Case {
+            name: "backspace",
+            names: &[],
+            steps: &[
+                (b"ab", "ab"),
+                (b"\x7f", "\x1b[1D\x1b[K"),
+                (b"\x08\x08\r", "\x1b[1D\x1b[K\r\n"),
+            ],
+            lines: &[""],
+        },
+        Case {
+            name: "delete",
+            names: &[],
+            steps: &[
+                (b"ab\x1b[D", "ab\x1b[1D"),
+                (b"\x1b[3~", "\x1b[K"),
+                (b"\x1b[3~\r", "\r\n"),
+            ],
+            lines: &["a"],
+        },
+        Case {
+            name: "insert in the middle",
+            names: &[],
+            steps: &[
+                (b"ac\x1b[D", "ac\x1b[1D"),
+                (b"b", "bc\x1b[K\x1b[1D"),
+                (b"\r", "\r\n"),
+            ],
+            lines: &["abc"],
+        },
+        Case {
+            name: "left and right stop at the ends",
+            names: &[],
+            steps: &[
+                (b"a\x1b[C", "a"),
+                (b"\x1b[D\x1b[D", "\x1b[1D"),
+                (b"\x1bOC", "\x1b[1C"),
+            ],
+            lines: &[],
+        },
+        Case {
+            name: "home and end",
+            names: &[],
+            steps: &[
+                (b"bc\x1b[H", "bc\x1b[2D"),
+                (b"a", "abc\x1b[K\x1b[2D"),
+                (b"\x1bOF", "\x1b[2C"),
+                (b"\x01", "\x1b[3D"),
+                (b"\x1b[4~d\r", "\x1b[3Cd\r\n"),
+            ],
+            lines: &["abcd"],
+        },
+        Case {
+            name: "escape sequence split across reads",
+            names: &[],
+            steps: &[(b"ab\x1b", "ab"), (b"[", ""), (b"D", "\x1b[1D")],
+            lines: &[],
+        },
+        Case {
+            name: "ctrl-u",
+            names: &[],
+            steps: &[
+                (b"ab cd\x1b[D", "ab cd\x1b[1D"),
+                (b"\x15", "\x1b[4Dd\x1b[K\x1b[1D"),
+                (b"\r", "\r\n"),
+            ],
+            lines: &["d"],
+        },
+        Case {
+            name: "ctrl-w",
+            names: &[],
+            steps: &[
+                (b"ab cd ", "ab cd "),
+                (b"\x17", "\x1b[3D\x1b[K"),
+                (b"\x17\r", "\x1b[3D\x1b[K\r\n"),
+            ],
+            lines: &[""],
+        },
+        Case {
+            name: "ctrl-c",
+            names: &[],
+            steps: &[(b"ab\x03", "ab^C\r\n"), (b"\r", "\r\n")],
+            lines: &[""],
+        },
+        Case {
+            name: "history up and down restores the draft",
+            names: &[],
+            steps: &[
+                (b"one\rtwo\r", "one\r\ntwo\r\n"),
+                (b"dr", "dr"),
+                (b"\x1b[A", "\x1b[2Dtwo\x1b[K"),
+                (b"\x1b[A", "\x1b[3Done\x1b[K"),
+                // nothing older than the first line
+                (b"\x1b[A", ""),
+                (b"\x1b[B", "\x1b[3Dtwo\x1b[K"),
+                (b"\x1b[B", "\x1b[3Ddr\x1b[K"),
+                (b"\x1b[B", ""),
+                (b"\r", "\r\n"),
+            ],
+            lines: &["one", "two", "dr"],
+        },
+        Case {
+            name: "history skips repeated lines",
+            names: &[],
+            steps: &[(b"a\ra\r", "a\r\na\r\n"), (b"\x1b[A", "a"), (b"\x1b[A", "")],
+            lines: &["a", "a"],
+        },
+        Case {
+            name: "tab with one candidate",
+            names: &["alice", "bob"],
+            steps: &[
+                (b"/msg bob,al", "/msg bob,al"),
+                (b"\t", "ice"),
+                (b"\r", "\r\n"),
+            ],
+            lines: &["/msg bob,alice"],
+        },
+        Case {
+            name: "tab with several candidates",
+            names: &["anna", "annika", "bob"],
+            steps: &[
+                (b"an", "an"),
+                // as far as they agree
+                (b"\t", "n"),
+                (b"\t", "\r\nanna  annika\r\nann"),
+                (b"a\r", "a\r\n"),
+            ],
+            lines: &["anna"],
+        },
+        Case {
+            name: "tab without candidates",
+            names: &["alice"],
+            steps: &[(b"x\t", "x\x07")],
+            lines: &[],
+        },
+        Case {
+            name: "full line",
+            names: &["alice"],
+            steps: &[
+                (&FULL_LINE, FULL_LINE_ECHO),
+                (b"b", "\x07"),
+                (b"\xc3\xa4\t", "\x07\x07"),
+                (b"\x7f", "\x1b[1D\x1b[K"),
+                (b"c", "c"),
+                (b"\x03", "^C\r\n"),
+            ],
+            lines: &[],
+        },
+        Case {
+            name: "multi-byte characters split across reads",
+            names: &[],
+            steps: &[
+                (b"\xc3", ""),
+                (b"\xa4", "ä"),
+                (b"\xe2", ""),
+                (b"\x82", ""),
+                (b"\xac\x7f", "€\x1b[1D\x1b[K"),
+                (b"\xf0\x9f", ""),
+                (b"\x98\x80\r", "😀\r\n"),
+            ],
+            lines: &["ä😀"],
+        },
+    ];
+
+    #[test]
+    fn table() {
+        for case in CASES {
+            let names: Vec<String> = case.names.iter().map(|name| name.to_string()).collect();
+            let mut editor = LineEditor::new();
+            let mut lines = Vec::new();
+            for (step, (bytes, echo)) in case.steps.iter().enumerate() {
+                let edited = editor.feed(bytes, &names);
+                assert_eq!(
+                    String::from_utf8(edited.echo).unwrap(),
+                    *echo,
+                    "{}, step {}",
+                    case.name,
+                    step + 1
+                );
+                lines.extend(edited.lines);
+            }
+            assert_eq!(lines, case.lines, "{}", case.name);
+        }
+    }
+}
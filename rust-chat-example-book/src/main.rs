@@ -1,12 +1,40 @@
 use dotenv::dotenv;
 use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::{signal::ctrl_c, sync::Notify, task::JoinSet};
 
 mod utils;
 use utils::BoxedResult;
 
+mod broker;
+use broker::{start_broker, Event};
+mod commands;
+mod history;
+use history::HistoryConfig;
+mod input_buffer;
+mod line_editor;
+mod message;
+mod nickname;
+mod offline;
+mod outbox;
+use outbox::{OverflowPolicy, QueueConfig};
+mod pairing;
+mod presence;
+mod rate_limit;
+use rate_limit::{Limit, RateLimits};
+mod rooms;
+mod storage;
+use storage::{JsonLinesStore, MemoryStore, Store};
+
 mod russh_connector;
 use russh_connector::start_russh_server;
 
+mod telnet_connector;
+use telnet_connector::accept_loop;
+mod telnet_protocol;
 
 // start by cargo run
 // then connect from different terminal instances using:    telnet localhost 8080
@@ -15,17 +43,225 @@ use russh_connector::start_russh_server;
 // NOTE:    the code from the book implemented here
 //          assumes that you write messages formatted like this:
 //          "other_user_1, other_user_2: Hello world!"
+//          type /help to see the other commands
+
+const DEFAULT_MOTD: &str = "Welcome to the chat!";
+
+// which listeners to start, set with SERVER_TRANSPORT=telnet|ssh|both in the env file
+#[derive(Clone, Copy)]
+enum Transports {
+    Telnet,
+    Ssh,
+    Both,
+}
+
+impl Transports {
+    fn from_env() -> Self {
+        let transports = env::var("SERVER_TRANSPORT").unwrap_or("both".to_string());
+        match transports.trim().to_lowercase().as_str() {
+            "telnet" => Transports::Telnet,
+            "ssh" => Transports::Ssh,
+            "both" => Transports::Both,
+            _ => panic!("SERVER_TRANSPORT must be telnet, ssh or both."),
+        }
+    }
+
+    fn telnet(self) -> bool {
+        matches!(self, Transports::Telnet | Transports::Both)
+    }
+
+    fn ssh(self) -> bool {
+        matches!(self, Transports::Ssh | Transports::Both)
+    }
+}
+
+// TELNET_HOST / SSH_HOST fall back to SERVER_HOST,
+// SSH_PORT falls back to SERVER_PORT and TELNET_PORT to 8080
+fn server_address(transport: &str, fallback_port: Option<String>) -> (String, u16) {
+    let host = env::var(format!("{transport}_HOST"))
+        .or(env::var("SERVER_HOST"))
+        .expect("SERVER_HOST must be named in env file (f.e. 0.0.0.0).");
+    let port = env::var(format!("{transport}_PORT"))
+        .ok()
+        .or(fallback_port)
+        .unwrap_or_else(|| panic!("{transport}_PORT must be named in env file (f.e. 2222)."));
+    let port = port
+        .parse::<u16>()
+        .unwrap_or_else(|_| panic!("{transport}_PORT must be a valid number."));
+    (host, port)
+}
+
+// ctrl + c in the server terminal, or SIGTERM f.e. from systemd or docker
+async fn shutdown_signal() -> BoxedResult<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = ctrl_c() => result?,
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    ctrl_c().await?;
+    Ok(())
+}
+
+// SHUTDOWN_COUNTDOWN in the env file gives clients that many seconds to say goodbye,
+// pressing ctrl + c again skips what is left of it
+async fn count_down(broker_sender: &UnboundedSender<Event>) {
+    let seconds: u64 = env_number("SHUTDOWN_COUNTDOWN", 0);
+
+    let countdown = async {
+        for left in (1..=seconds).rev() {
+            if left == seconds || left % 10 == 0 || left <= 5 {
+                let _ = broker_sender.send(Event::Broadcast {
+                    message: format!("The server shuts down in {left} seconds."),
+                });
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    };
+    tokio::select! {
+        _ = countdown => (),
+        _ = ctrl_c() => (),
+    }
+}
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(number) => number
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be a valid number.")),
+        Err(_) => default,
+    }
+}
+
+// CLIENT_QUEUE_CAPACITY messages may wait for a client that reads slowly,
+// after that CLIENT_QUEUE_POLICY decides: drop-oldest, drop-newest or disconnect
+fn queue_config() -> QueueConfig {
+    let capacity = env_number("CLIENT_QUEUE_CAPACITY", 256);
+    let policy = env::var("CLIENT_QUEUE_POLICY")
+        .map(|policy| {
+            policy
+                .parse::<OverflowPolicy>()
+                .unwrap_or_else(|e| panic!("{e}"))
+        })
+        .unwrap_or(OverflowPolicy::DropOldest);
+    QueueConfig { capacity, policy }
+}
+
+// HISTORY_SIZE messages are kept per room and per pair of clients,
+// HISTORY_REPLAY of them are shown to whoever enters a room
+fn history_config() -> HistoryConfig {
+    HistoryConfig {
+        size: env_number("HISTORY_SIZE", 100),
+        replay: env_number("HISTORY_REPLAY", 10),
+    }
+}
+
+// with STORE_DIR in the env file, history and rooms are kept there and survive a restart,
+// files are rotated after STORE_SEGMENT_BYTES and compacted after STORE_COMPACT_SEGMENTS of them
+fn store() -> Box<dyn Store> {
+    match env::var("STORE_DIR") {
+        Ok(dir) => Box::new(
+            JsonLinesStore::open(
+                &dir,
+                env_number("STORE_SEGMENT_BYTES", 1024 * 1024),
+                env_number("STORE_COMPACT_SEGMENTS", 4),
+            )
+            .unwrap_or_else(|e| panic!("STORE_DIR {dir:?} could not be opened: {e}")),
+        ),
+        Err(_) => Box::new(MemoryStore::default()),
+    }
+}
+
+// MOTD_FILE in the env file names a text file every client sees when it connects
+fn motd() -> Arc<str> {
+    match env::var("MOTD_FILE") {
+        Ok(path) => fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("MOTD_FILE {path:?} could not be read: {e}"))
+            .trim_end()
+            .into(),
+        Err(_) => DEFAULT_MOTD.into(),
+    }
+}
+
+// RATE_<DIRECT|BROADCAST|IP>_BURST lines may be sent at once,
+// after that RATE_<...>_PER_SECOND more each second
+fn rate_limits() -> RateLimits {
+    let limit = |name: &str, burst: u32, per_second: f64| Limit {
+        burst: env_number(&format!("RATE_{name}_BURST"), burst),
+        per_second: env_number(&format!("RATE_{name}_PER_SECOND"), per_second),
+    };
+    RateLimits {
+        direct: limit("DIRECT", 10, 2.0),
+        broadcast: limit("BROADCAST", 3, 0.2),
+        per_ip: limit("IP", 30, 5.0),
+        mute_after: env_number("RATE_MUTE_AFTER", 3),
+        mute_for: Duration::from_secs(env_number("RATE_MUTE_SECONDS", 30)),
+    }
+}
 
 #[tokio::main]
 pub(crate) async fn main() -> BoxedResult<()> {
     dotenv().ok();
 
-    let host =
-        env::var("SERVER_HOST").expect("SERVER_HOST must be named in env file (f.e. 0.0.0.0).");
-    let port = env::var("SERVER_PORT").expect("SERVER_PORT must be named in env file (f.e. 2222).");
-    let port = port
-        .parse::<u16>()
-        .expect("SERVER_PORT must be a valid number.");
+    let transports = Transports::from_env();
+    let motd = motd();
+
+    let (broker_sender, broker) =
+        start_broker(queue_config(), rate_limits(), history_config(), store());
+    let stop_accepting = Arc::new(Notify::new());
+    let shutdown_notification = Arc::new(Notify::new());
+    let mut servers = JoinSet::new();
+
+    if transports.telnet() {
+        let addr = server_address("TELNET", Some("8080".to_string()));
+        servers.spawn(accept_loop(
+            addr,
+            broker_sender.clone(),
+            stop_accepting.clone(),
+            shutdown_notification.clone(),
+            motd.clone(),
+        ));
+    }
+    if transports.ssh() {
+        let addr = server_address("SSH", env::var("SERVER_PORT").ok());
+        servers.spawn(start_russh_server(
+            addr,
+            broker_sender.clone(),
+            stop_accepting.clone(),
+            shutdown_notification.clone(),
+            motd.clone(),
+        ));
+    }
+
+    // a listener that fails (f.e. because its port is taken) takes the others down with it
+    let mut result = Ok(());
+    let signal = tokio::select! {
+        signal = shutdown_signal() => Some(signal),
+        Some(server) = servers.join_next() => {
+            result = server?;
+            None
+        },
+    };
+    // nobody new gets in while the countdown runs
+    stop_accepting.notify_waiters();
+    if let Some(signal) = signal {
+        signal?;
+        println!("Shutting down server...");
+        count_down(&broker_sender).await;
+    }
 
-    start_russh_server((host, port)).await
+    // first everyone gets the broker's goodbye, then the servers close the connections
+    broker_sender.send(Event::Shutdown)?;
+    broker.await?;
+    shutdown_notification.notify_waiters();
+    while let Some(server) = servers.join_next().await {
+        if let Err(e) = server? {
+            eprintln!("{e}");
+        }
+    }
+    result
 }
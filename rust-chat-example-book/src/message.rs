@@ -0,0 +1,104 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+// What the broker delivers to a client.
+// Terminals get it rendered as text, other programs can ask for json with `/format json`,
+// one object per line, f.e.
+//      {"type":"chat","from":"bob","kind":"direct","sent_at":"2024-09-01T12:00:00+02:00","text":"Hi!"}
+// Replayed messages look the same, except for "type":"history".
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MessageKind {
+    Direct,
+    Room { room: String },
+}
+
+// also kept by the store, see storage.rs
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub from: String,
+    #[serde(flatten)]
+    pub kind: MessageKind,
+    pub sent_at: DateTime<Local>,
+    pub text: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Outgoing {
+    Chat(ChatMessage),
+    // sent before the client was around, see /history
+    History(ChatMessage),
+    // from the server itself, f.e. errors or the client list
+    Notice { text: String },
+}
+
+#[derive(Clone, Copy)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+// a message as it is queued for one client, transports may only reflow `Text`
+pub struct Rendered {
+    pub text: String,
+    pub format: OutputFormat,
+}
+
+impl ChatMessage {
+    pub fn new(from: &str, kind: MessageKind, text: &str) -> Self {
+        ChatMessage {
+            from: from.to_string(),
+            kind,
+            sent_at: Local::now(),
+            text: text.to_string(),
+        }
+    }
+}
+
+impl Outgoing {
+    pub fn notice(text: impl Into<String>) -> Self {
+        Outgoing::Notice { text: text.into() }
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Text => self.to_text(),
+            OutputFormat::Json => {
+                serde_json::to_string(self).expect("Outgoing messages can always be serialized.")
+            }
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Outgoing::Chat(message) => {
+                let time = message.sent_at.format("%H:%M:%S");
+                match &message.kind {
+                    MessageKind::Direct => {
+                        format!("[{time}] {} -> you: {}", message.from, message.text)
+                    }
+                    MessageKind::Room { room } => {
+                        format!("[{time}] #{room} {}: {}", message.from, message.text)
+                    }
+                }
+            }
+            // with the date, it may be from another day,
+            // and direct messages may be the client's own
+            Outgoing::History(message) => {
+                let time = message.sent_at.format("%Y-%m-%d %H:%M:%S");
+                match &message.kind {
+                    MessageKind::Direct => {
+                        format!("[{time}] (history) {}: {}", message.from, message.text)
+                    }
+                    MessageKind::Room { room } => format!(
+                        "[{time}] (history) #{room} {}: {}",
+                        message.from, message.text
+                    ),
+                }
+            }
+            Outgoing::Notice { text } => text.clone(),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{skeleton, MixedScript};
+
+// The rules every name has to follow, no matter which transport the client uses.
+// Names show up in the "name_1, name_2: message" syntax and in every delivered message,
+// so separators, whitespace and control characters are out, and so are names
+// that only look like another one, f.e. "bob" written with a cyrillic "о".
+
+pub const MIN_LENGTH: usize = 2;
+pub const MAX_LENGTH: usize = 20;
+
+// "all" is the broadcast keyword, the others could be mistaken for messages from the server
+const RESERVED_NAMES: &[&str] = &["all", "admin", "server"];
+
+pub enum NameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter(char),
+    InvalidStart,
+    MixedScripts,
+    Reserved(String),
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::TooShort => write!(f, "A name needs at least {MIN_LENGTH} characters."),
+            NameError::TooLong => write!(f, "A name can have at most {MAX_LENGTH} characters."),
+            NameError::InvalidCharacter(character) => write!(
+                f,
+                "A name cannot contain {character:?}, use letters, digits, '_', '-' or '.'."
+            ),
+            NameError::InvalidStart => write!(f, "A name has to start with a letter or digit."),
+            NameError::MixedScripts => {
+                write!(f, "A name cannot mix letters of different alphabets.")
+            }
+            NameError::Reserved(name) => write!(f, "The name {name} is reserved."),
+        }
+    }
+}
+
+// returns the name the client will be known as
+pub fn validate(name: &str) -> Result<String, NameError> {
+    // "ｂｏｂ" and "bob" are the same name, so is "e" followed by a combining accent and "é"
+    let name: String = name.trim().nfkc().collect();
+
+    let length = name.chars().count();
+    if length < MIN_LENGTH {
+        return Err(NameError::TooShort);
+    }
+    if length > MAX_LENGTH {
+        return Err(NameError::TooLong);
+    }
+    if let Some(character) = name.chars().find(|character| !is_allowed(*character)) {
+        return Err(NameError::InvalidCharacter(character));
+    }
+    if !name.starts_with(char::is_alphanumeric) {
+        return Err(NameError::InvalidStart);
+    }
+    if !name.as_str().is_single_script() {
+        return Err(NameError::MixedScripts);
+    }
+
+    let name_skeleton = confusable_skeleton(&name);
+    if let Some(reserved) = RESERVED_NAMES
+        .iter()
+        .find(|reserved| confusable_skeleton(reserved) == name_skeleton)
+    {
+        return Err(NameError::Reserved(reserved.to_string()));
+    }
+    Ok(name)
+}
+
+// names that look alike have the same skeleton, f.e. "Bob" and "bob",
+// "bob" and "bоb" (cyrillic "о") or "rn" and "m"
+pub fn confusable_skeleton(name: &str) -> String {
+    skeleton(&name.to_lowercase()).collect()
+}
+
+fn is_allowed(character: char) -> bool {
+    character.is_alphanumeric() || matches!(character, '_' | '-' | '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_names() {
+        let cases = [
+            ("bob", Ok("bob")),
+            ("  bob ", Ok("bob")),
+            ("ｂｏｂ", Ok("bob")),
+            ("b", Err("A name needs at least 2 characters.")),
+            ("ｂ", Err("A name needs at least 2 characters.")),
+            ("bo", Ok("bo")),
+            ("abcdefghijklmnopqrst", Ok("abcdefghijklmnopqrst")),
+            (
+                "abcdefghijklmnopqrstu",
+                Err("A name can have at most 20 characters."),
+            ),
+            // characters count, not bytes
+            ("ééééééééééééééééééé", Ok("ééééééééééééééééééé")),
+            ("bob_2.0-x", Ok("bob_2.0-x")),
+            ("2bob", Ok("2bob")),
+            ("Ελένη", Ok("Ελένη")),
+            (
+                "bob,alice",
+                Err("A name cannot contain ',', use letters, digits, '_', '-' or '.'."),
+            ),
+            (
+                "bob smith",
+                Err("A name cannot contain ' ', use letters, digits, '_', '-' or '.'."),
+            ),
+            (
+                "bob:",
+                Err("A name cannot contain ':', use letters, digits, '_', '-' or '.'."),
+            ),
+            ("_bob", Err("A name has to start with a letter or digit.")),
+            (".bob", Err("A name has to start with a letter or digit.")),
+            (
+                "bоb",
+                Err("A name cannot mix letters of different alphabets."),
+            ),
+            ("all", Err("The name all is reserved.")),
+            ("Admin", Err("The name admin is reserved.")),
+            ("SERVER", Err("The name server is reserved.")),
+            ("a11", Err("The name all is reserved.")),
+            // a cyrillic "а", which is caught as mixed scripts before it gets compared
+            (
+                "аll",
+                Err("A name cannot mix letters of different alphabets."),
+            ),
+        ];
+        for (name, expected) in cases {
+            let validated = validate(name).map_err(|error| error.to_string());
+            let expected = expected.map(str::to_string).map_err(str::to_string);
+            assert_eq!(validated, expected, "{name:?}");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::broker::{send_notice, Clients};
+use crate::history::History;
+use crate::message::{ChatMessage, Outgoing};
+use crate::storage::Record;
+
+// Direct messages for clients that were here before, but aren't right now.
+// They get them the next time they join, after a summary of who wrote while they were away.
+// Names nobody ever used are an error, so typos don't disappear silently.
+// A name belongs to whoever is connected with it, nothing checks that it is the same person
+// as before: telnet has no login at all and every ssh client shares one key. So whoever joins
+// as a name next gets the messages waiting for it, and can read that name's direct messages
+// with /history, also after a restart. The notice to the sender says so.
+
+// per client, so a flood can't fill the store
+const MAX_WAITING: usize = 100;
+
+#[derive(Default)]
+pub struct Mailboxes {
+    // everyone who ever joined
+    known: BTreeSet<String>,
+    waiting: HashMap<String, Vec<ChatMessage>>,
+}
+
+impl Mailboxes {
+    pub fn is_known(&self, name: &str) -> bool {
+        self.known.contains(name)
+    }
+
+    // for records from the store
+    pub fn restore(&mut self, record: Record) {
+        match record {
+            Record::KnownUser { name } => {
+                self.known.insert(name);
+            }
+            Record::OfflineMessage { to, message } => {
+                self.waiting.entry(to).or_default().push(message)
+            }
+            Record::OfflineDelivered { to } => {
+                self.waiting.remove(&to);
+            }
+            _ => (),
+        }
+    }
+
+    // what has to be kept when the store is compacted
+    pub fn records(&self) -> Vec<Record> {
+        let known = self
+            .known
+            .iter()
+            .map(|name| Record::KnownUser { name: name.clone() });
+        let waiting = self.waiting.iter().flat_map(|(to, messages)| {
+            messages.iter().map(|message| Record::OfflineMessage {
+                to: to.clone(),
+                message: message.clone(),
+            })
+        });
+        known.chain(waiting).collect()
+    }
+}
+
+// keeps `message` for `to`, who has to be known but not online,
+// returns false if their mailbox is full
+pub fn leave_message(
+    mailboxes: &mut Mailboxes,
+    clients: &Clients,
+    history: &mut History,
+    to: &str,
+    message: &ChatMessage,
+) -> bool {
+    let waiting = mailboxes.waiting.entry(to.to_string()).or_default();
+    if waiting.len() >= MAX_WAITING {
+        send_notice(
+            clients,
+            &message.from,
+            format!("{to} is offline and has too many messages waiting already."),
+        );
+        return false;
+    }
+    waiting.push(message.clone());
+    history.persist(Record::OfflineMessage {
+        to: to.to_string(),
+        message: message.clone(),
+    });
+    send_notice(
+        clients,
+        &message.from,
+        format!(
+            "{to} is offline, they will get your message when they are back. \
+            Names aren't protected, so whoever joins as {to} next gets it."
+        ),
+    );
+    true
+}
+
+// called once `name` joined, remembers them and hands over what is waiting for them
+pub fn welcome(mailboxes: &mut Mailboxes, clients: &Clients, history: &mut History, name: &str) {
+    if mailboxes.known.insert(name.to_string()) {
+        history.persist(Record::KnownUser {
+            name: name.to_string(),
+        });
+    }
+    let messages = match mailboxes.waiting.remove(name) {
+        Some(messages) => messages,
+        None => return,
+    };
+    history.persist(Record::OfflineDelivered {
+        to: name.to_string(),
+    });
+
+    let mut senders: BTreeMap<&str, usize> = BTreeMap::new();
+    for message in &messages {
+        *senders.entry(&message.from).or_default() += 1;
+    }
+    let senders: Vec<String> = senders
+        .iter()
+        .map(|(sender, count)| format!("{count} from {sender}"))
+        .collect();
+    let count = match messages.len() {
+        1 => "a message".to_string(),
+        count => format!("{count} messages"),
+    };
+    send_notice(
+        clients,
+        name,
+        format!(
+            "While you were away, you got {count}: {}.",
+            senders.join(", ")
+        ),
+    );
+    if let Some(client) = clients.get(name) {
+        for message in messages {
+            client.deliver(&Outgoing::History(message));
+        }
+    }
+}
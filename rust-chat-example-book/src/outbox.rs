@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::message::Rendered;
+
+// The queue between the broker and a client's writer.
+// A client that stops reading must not make the server keep every message for it,
+// so the queue only holds `capacity` messages and then follows its `OverflowPolicy`.
+
+#[derive(Clone, Copy)]
+pub enum OverflowPolicy {
+    // the client misses the oldest messages, but gets the newest ones
+    DropOldest,
+    // the client gets the messages it is behind on, new ones are lost
+    DropNewest,
+    // a client that can't keep up is disconnected
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy.trim().to_lowercase().as_str() {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!(
+                "{policy:?} is no overflow policy, use drop-oldest, drop-newest or disconnect."
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+struct State {
+    messages: VecDeque<Rendered>,
+    // either side is gone
+    closed: bool,
+    // the client was too slow and the policy is `Disconnect`
+    overflowed: bool,
+    dropped: u64,
+}
+
+struct Shared {
+    config: QueueConfig,
+    state: Mutex<State>,
+    changed: Notify,
+    // separate, so a writer stuck on a slow client can be woken up
+    overflow: Notify,
+}
+
+pub struct OutboxSender {
+    shared: Arc<Shared>,
+}
+
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+}
+
+pub struct Closed;
+
+pub fn outbox(config: QueueConfig) -> (OutboxSender, OutboxReceiver) {
+    let shared = Arc::new(Shared {
+        config,
+        state: Mutex::new(State {
+            messages: VecDeque::new(),
+            closed: false,
+            overflowed: false,
+            dropped: 0,
+        }),
+        changed: Notify::new(),
+        overflow: Notify::new(),
+    });
+    (
+        OutboxSender {
+            shared: shared.clone(),
+        },
+        OutboxReceiver { shared },
+    )
+}
+
+impl OutboxSender {
+    // never waits, a full queue is handled by the policy instead
+    pub fn send(&self, message: Rendered) -> Result<(), Closed> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+
+        if state.messages.len() >= self.shared.config.capacity {
+            match self.shared.config.policy {
+                OverflowPolicy::DropOldest => {
+                    state.messages.pop_front();
+                    state.dropped += 1;
+                }
+                OverflowPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return Ok(());
+                }
+                OverflowPolicy::Disconnect => {
+                    state.dropped += state.messages.len() as u64 + 1;
+                    state.messages.clear();
+                    state.closed = true;
+                    state.overflowed = true;
+                    drop(state);
+                    self.shared.changed.notify_one();
+                    self.shared.overflow.notify_one();
+                    return Err(Closed);
+                }
+            }
+        }
+
+        state.messages.push_back(message);
+        drop(state);
+        self.shared.changed.notify_one();
+        Ok(())
+    }
+
+    // how many messages never made it to the client
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.shared.state.lock().unwrap().overflowed
+    }
+}
+
+impl Drop for OutboxSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+impl OutboxReceiver {
+    // None once the sender is gone and everything was received, or right away on overflow
+    pub async fn recv(&self) -> Option<Rendered> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(message) = state.messages.pop_front() {
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // a notification sent since the lock was released is stored, so none is missed
+            self.shared.changed.notified().await;
+        }
+    }
+
+    // waits until the client is too slow, for the policy `Disconnect`
+    pub async fn overflowed(&self) {
+        while !self.shared.state.lock().unwrap().overflowed {
+            self.shared.overflow.notified().await;
+        }
+    }
+}
+
+impl Drop for OutboxReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::message::OutputFormat;
+
+    fn queue(policy: OverflowPolicy) -> (OutboxSender, OutboxReceiver) {
+        outbox(QueueConfig {
+            capacity: 2,
+            policy,
+        })
+    }
+
+    fn send(sender: &OutboxSender, text: &str) -> Result<(), Closed> {
+        sender.send(Rendered {
+            text: text.to_string(),
+            format: OutputFormat::Text,
+        })
+    }
+
+    // everything that is queued, once the sender is gone
+    async fn received(sender: OutboxSender, receiver: OutboxReceiver) -> Vec<String> {
+        drop(sender);
+        let mut texts = Vec::new();
+        while let Some(message) = receiver.recv().await {
+            texts.push(message.text);
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let (sender, receiver) = queue(OverflowPolicy::DropOldest);
+        for text in ["1", "2", "3", "4"] {
+            assert!(send(&sender, text).is_ok());
+        }
+        assert_eq!(sender.dropped(), 2);
+        assert!(!sender.overflowed());
+        assert_eq!(received(sender, receiver).await, ["3", "4"]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let (sender, receiver) = queue(OverflowPolicy::DropNewest);
+        for text in ["1", "2", "3", "4"] {
+            assert!(send(&sender, text).is_ok());
+        }
+        assert_eq!(sender.dropped(), 2);
+        assert!(!sender.overflowed());
+        assert_eq!(received(sender, receiver).await, ["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let (sender, receiver) = queue(OverflowPolicy::Disconnect);
+        assert!(send(&sender, "1").is_ok());
+        assert!(send(&sender, "2").is_ok());
+        assert!(send(&sender, "3").is_err());
+        assert!(send(&sender, "4").is_err());
+        // the queued messages count too, the client never gets them
+        assert_eq!(sender.dropped(), 3);
+        assert!(sender.overflowed());
+        assert_eq!(received(sender, receiver).await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn received_messages_are_not_dropped() {
+        let (sender, receiver) = queue(OverflowPolicy::Disconnect);
+        for text in ["1", "2", "3", "4"] {
+            assert!(send(&sender, text).is_ok());
+            assert_eq!(receiver.recv().await.unwrap().text, text);
+        }
+        assert_eq!(sender.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn recv_ends_once_the_sender_is_gone() {
+        let (sender, receiver) = queue(OverflowPolicy::DropOldest);
+        let writer = tokio::spawn(async move { receiver.recv().await.map(|message| message.text) });
+        tokio::task::yield_now().await;
+        assert!(send(&sender, "1").is_ok());
+        assert_eq!(writer.await.unwrap().as_deref(), Some("1"));
+
+        let (sender, receiver) = queue(OverflowPolicy::DropOldest);
+        let writer = tokio::spawn(async move { receiver.recv().await.is_none() });
+        tokio::task::yield_now().await;
+        drop(sender);
+        assert!(writer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn overflow_wakes_a_waiting_writer() {
+        let (sender, receiver) = queue(OverflowPolicy::Disconnect);
+        let writer = tokio::spawn(async move { receiver.overflowed().await });
+        tokio::task::yield_now().await;
+        for text in ["1", "2", "3"] {
+            let _ = send(&sender, text);
+        }
+        let woken = tokio::time::timeout(Duration::from_secs(1), writer).await;
+        assert!(woken.is_ok(), "the writer is still waiting");
+    }
+}
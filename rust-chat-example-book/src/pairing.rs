@@ -0,0 +1,385 @@
+use crate::broker::{send_notice, Clients};
+
+// Exclusive conversations between two clients:
+// 1. alice types "/connect bob"
+// 2. bob is asked and types "/accept alice" (or "/decline alice")
+//      -> only if bob isn't already connected or waiting for someone else,
+//         otherwise alice receives "Cannot connect to bob at the moment"
+// 3. from now on every line alice or bob type without a command goes to the other one
+// 4. "/disconnect" ends it for both
+
+#[derive(Clone, Default)]
+pub enum Pairing {
+    #[default]
+    Alone,
+    // waiting for `with` to accept
+    Requested {
+        with: String,
+    },
+    Connected {
+        with: String,
+    },
+}
+
+impl Pairing {
+    pub fn partner(&self) -> Option<&String> {
+        match self {
+            Pairing::Connected { with } => Some(with),
+            _ => None,
+        }
+    }
+}
+
+pub fn connect(clients: &mut Clients, name: &str, with: &str) {
+    if name == with {
+        return send_notice(clients, name, "You cannot connect to yourself.");
+    }
+    let (own_pairing, other_pairing) = match (clients.get(name), clients.get(with)) {
+        (Some(own), Some(other)) => (own.pairing.clone(), other.pairing.clone()),
+        (Some(_), None) => return send_notice(clients, name, format!("{with} is not online.")),
+        _ => return,
+    };
+
+    match (own_pairing, other_pairing) {
+        (Pairing::Connected { with: partner }, _) => send_notice(
+            clients,
+            name,
+            format!("You are connected to {partner}, type /disconnect first."),
+        ),
+        (Pairing::Requested { with: requested }, _) => send_notice(
+            clients,
+            name,
+            format!("You are waiting for {requested}, type /disconnect to cancel that first."),
+        ),
+        // both asked for each other at the same time
+        (Pairing::Alone, Pairing::Requested { with: requested }) if requested == name => {
+            remove_request(clients, name, with);
+            start(clients, with, name)
+        }
+        (Pairing::Alone, Pairing::Alone) => {
+            set_pairing(
+                clients,
+                name,
+                Pairing::Requested {
+                    with: with.to_string(),
+                },
+            );
+            if let Some(other) = clients.get_mut(with) {
+                other.connect_requests.retain(|requester| requester != name);
+                other.connect_requests.push(name.to_string());
+            }
+            send_notice(
+                clients,
+                with,
+                format!(
+                    "{name} wants to connect with you, type /accept {name} or /decline {name}."
+                ),
+            );
+            send_notice(clients, name, format!("Waiting for {with} to accept..."));
+        }
+        (Pairing::Alone, _) => send_notice(
+            clients,
+            name,
+            format!("Cannot connect to {with} at the moment."),
+        ),
+    }
+}
+
+// `requester` may be left out when only one client is waiting
+pub fn accept(clients: &mut Clients, name: &str, requester: Option<String>) {
+    let requester = match pick_request(clients, name, requester) {
+        Some(requester) => requester,
+        None => return,
+    };
+    if let Some(partner) = clients
+        .get(name)
+        .and_then(|client| client.pairing.partner())
+    {
+        let notice = format!("You are connected to {partner}, type /disconnect first.");
+        return send_notice(clients, name, notice);
+    }
+
+    remove_request(clients, name, &requester);
+    let still_waiting = clients.get(&requester).is_some_and(
+        |client| matches!(&client.pairing, Pairing::Requested { with } if with == name),
+    );
+    match still_waiting {
+        true => start(clients, &requester, name),
+        false => send_notice(
+            clients,
+            name,
+            format!("{requester} is not waiting anymore."),
+        ),
+    }
+}
+
+pub fn decline(clients: &mut Clients, name: &str, requester: Option<String>) {
+    let requester = match pick_request(clients, name, requester) {
+        Some(requester) => requester,
+        None => return,
+    };
+
+    remove_request(clients, name, &requester);
+    set_pairing(clients, &requester, Pairing::Alone);
+    send_notice(clients, &requester, format!("{name} declined to connect."));
+    send_notice(clients, name, format!("You declined {requester}."));
+}
+
+// ends a connection or withdraws a request, `leaving` is true when the client goes offline
+pub fn disconnect(clients: &mut Clients, name: &str, leaving: bool) {
+    let pairing = match clients.get(name) {
+        Some(client) => client.pairing.clone(),
+        None => return,
+    };
+
+    match pairing {
+        Pairing::Connected { with } => {
+            set_pairing(clients, name, Pairing::Alone);
+            set_pairing(clients, &with, Pairing::Alone);
+            send_notice(
+                clients,
+                &with,
+                format!("{name} ended the conversation with you."),
+            );
+            send_notice(
+                clients,
+                name,
+                format!("You ended the conversation with {with}."),
+            );
+        }
+        Pairing::Requested { with } => {
+            set_pairing(clients, name, Pairing::Alone);
+            remove_request(clients, &with, name);
+            send_notice(
+                clients,
+                &with,
+                format!("{name} doesn't want to connect anymore."),
+            );
+            send_notice(clients, name, format!("You stopped waiting for {with}."));
+        }
+        Pairing::Alone if !leaving => {
+            send_notice(clients, name, "You are not connected to anyone.")
+        }
+        Pairing::Alone => (),
+    }
+
+    if leaving {
+        // nobody can accept requests from someone who is gone
+        let requesters = match clients.get_mut(name) {
+            Some(client) => std::mem::take(&mut client.connect_requests),
+            None => Vec::new(),
+        };
+        for requester in requesters {
+            set_pairing(clients, &requester, Pairing::Alone);
+            send_notice(
+                clients,
+                &requester,
+                format!("{name} left before accepting."),
+            );
+        }
+    }
+}
+
+fn start(clients: &mut Clients, requester: &str, accepter: &str) {
+    set_pairing(
+        clients,
+        requester,
+        Pairing::Connected {
+            with: accepter.to_string(),
+        },
+    );
+    set_pairing(
+        clients,
+        accepter,
+        Pairing::Connected {
+            with: requester.to_string(),
+        },
+    );
+    for (name, with) in [(requester, accepter), (accepter, requester)] {
+        send_notice(
+            clients,
+            name,
+            format!("You are now connected with {with}, type /disconnect to end it."),
+        );
+    }
+}
+
+fn pick_request(clients: &Clients, name: &str, requester: Option<String>) -> Option<String> {
+    let requests = &clients.get(name)?.connect_requests;
+    let picked = match (requester, &requests[..]) {
+        (Some(requester), _) if requests.contains(&requester) => Ok(requester),
+        (Some(requester), _) => Err(format!("{requester} didn't ask to connect with you.")),
+        (None, []) => Err("Nobody asked to connect with you.".to_string()),
+        (None, [requester]) => Ok(requester.clone()),
+        (None, _) => Err(format!(
+            "Several clients want to connect with you, pick one of: {}",
+            requests.join(", ")
+        )),
+    };
+    match picked {
+        Ok(requester) => Some(requester),
+        Err(error) => {
+            send_notice(clients, name, error);
+            None
+        }
+    }
+}
+
+fn remove_request(clients: &mut Clients, name: &str, requester: &str) {
+    if let Some(client) = clients.get_mut(name) {
+        client
+            .connect_requests
+            .retain(|request| request != requester);
+    }
+}
+
+fn set_pairing(clients: &mut Clients, name: &str, pairing: Pairing) {
+    if let Some(client) = clients.get_mut(name) {
+        client.pairing = pairing;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::broker::tests::TestClients;
+
+    fn partner(chat: &TestClients, name: &str) -> Option<String> {
+        chat.clients[name].pairing.partner().cloned()
+    }
+
+    fn waits_for(chat: &TestClients, name: &str) -> Option<String> {
+        match &chat.clients[name].pairing {
+            Pairing::Requested { with } => Some(with.clone()),
+            _ => None,
+        }
+    }
+
+    fn got(sent: &HashMap<String, Vec<String>>, name: &str, notice: &str) -> bool {
+        sent[name].iter().any(|line| line == notice)
+    }
+
+    #[tokio::test]
+    async fn connect_and_accept() {
+        let mut chat = TestClients::new(&["alice", "bob"]);
+        connect(&mut chat.clients, "alice", "bob");
+        assert_eq!(waits_for(&chat, "alice").as_deref(), Some("bob"));
+        assert_eq!(chat.clients["bob"].connect_requests, ["alice"]);
+
+        accept(&mut chat.clients, "bob", None);
+        assert_eq!(partner(&chat, "alice").as_deref(), Some("bob"));
+        assert_eq!(partner(&chat, "bob").as_deref(), Some("alice"));
+        assert!(chat.clients["bob"].connect_requests.is_empty());
+
+        let sent = chat.sent().await;
+        assert!(got(
+            &sent,
+            "bob",
+            "alice wants to connect with you, type /accept alice or /decline alice."
+        ));
+        assert!(got(&sent, "alice", "Waiting for bob to accept..."));
+        assert!(got(
+            &sent,
+            "alice",
+            "You are now connected with bob, type /disconnect to end it."
+        ));
+    }
+
+    #[tokio::test]
+    async fn decline() {
+        let mut chat = TestClients::new(&["alice", "bob"]);
+        connect(&mut chat.clients, "alice", "bob");
+        super::decline(&mut chat.clients, "bob", Some("alice".to_string()));
+        assert_eq!(waits_for(&chat, "alice"), None);
+        assert!(chat.clients["bob"].connect_requests.is_empty());
+
+        accept(&mut chat.clients, "bob", None);
+        assert_eq!(partner(&chat, "bob"), None);
+
+        let sent = chat.sent().await;
+        assert!(got(&sent, "alice", "bob declined to connect."));
+        assert!(got(&sent, "bob", "You declined alice."));
+        assert!(got(&sent, "bob", "Nobody asked to connect with you."));
+    }
+
+    #[tokio::test]
+    async fn disconnect_ends_it_for_both() {
+        let mut chat = TestClients::new(&["alice", "bob"]);
+        connect(&mut chat.clients, "alice", "bob");
+        accept(&mut chat.clients, "bob", Some("alice".to_string()));
+        disconnect(&mut chat.clients, "bob", false);
+        assert_eq!(partner(&chat, "alice"), None);
+        assert_eq!(partner(&chat, "bob"), None);
+
+        disconnect(&mut chat.clients, "bob", false);
+
+        let sent = chat.sent().await;
+        assert!(got(&sent, "alice", "bob ended the conversation with you."));
+        assert!(got(&sent, "bob", "You ended the conversation with alice."));
+        assert!(got(&sent, "bob", "You are not connected to anyone."));
+    }
+
+    #[tokio::test]
+    async fn disconnect_withdraws_a_request() {
+        let mut chat = TestClients::new(&["alice", "bob"]);
+        connect(&mut chat.clients, "alice", "bob");
+        disconnect(&mut chat.clients, "alice", false);
+        assert_eq!(waits_for(&chat, "alice"), None);
+        assert!(chat.clients["bob"].connect_requests.is_empty());
+
+        let sent = chat.sent().await;
+        assert!(got(&sent, "bob", "alice doesn't want to connect anymore."));
+        assert!(got(&sent, "alice", "You stopped waiting for bob."));
+    }
+
+    #[tokio::test]
+    async fn both_connect_at_once() {
+        let mut chat = TestClients::new(&["alice", "bob"]);
+        connect(&mut chat.clients, "alice", "bob");
+        connect(&mut chat.clients, "bob", "alice");
+        assert_eq!(partner(&chat, "alice").as_deref(), Some("bob"));
+        assert_eq!(partner(&chat, "bob").as_deref(), Some("alice"));
+        assert!(chat.clients["bob"].connect_requests.is_empty());
+        assert!(chat.clients["alice"].connect_requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn busy_clients_cannot_be_connected_to() {
+        let mut chat = TestClients::new(&["alice", "bob", "carol", "dave"]);
+        connect(&mut chat.clients, "alice", "bob");
+        accept(&mut chat.clients, "bob", None);
+        connect(&mut chat.clients, "carol", "bob");
+        assert_eq!(waits_for(&chat, "carol"), None);
+        assert!(chat.clients["bob"].connect_requests.is_empty());
+
+        // dave waits for carol, so he can't be asked either
+        connect(&mut chat.clients, "dave", "carol");
+        disconnect(&mut chat.clients, "bob", false);
+        connect(&mut chat.clients, "bob", "dave");
+        assert_eq!(waits_for(&chat, "bob"), None);
+        assert_eq!(waits_for(&chat, "dave").as_deref(), Some("carol"));
+
+        let sent = chat.sent().await;
+        assert!(got(&sent, "carol", "Cannot connect to bob at the moment."));
+        assert!(got(&sent, "bob", "Cannot connect to dave at the moment."));
+    }
+
+    #[tokio::test]
+    async fn leaving_cleans_up() {
+        let mut chat = TestClients::new(&["alice", "bob", "carol"]);
+        connect(&mut chat.clients, "carol", "alice");
+        // alice would rather talk to bob, and leaves carol waiting
+        connect(&mut chat.clients, "alice", "bob");
+        accept(&mut chat.clients, "bob", None);
+
+        chat.leave("alice");
+        assert_eq!(partner(&chat, "bob"), None);
+        assert_eq!(waits_for(&chat, "carol"), None);
+
+        let sent = chat.sent().await;
+        assert!(got(&sent, "bob", "alice ended the conversation with you."));
+        assert!(got(&sent, "carol", "alice left before accepting."));
+    }
+}
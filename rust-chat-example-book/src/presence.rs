@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+
+use crate::broker::{send_notice, Clients};
+
+// Who is online and what they are up to:
+// everyone hears when someone joins or leaves, "/who" lists every client
+// with its transport, when it connected and how long it has been idle,
+// and "/away <reason>" tells everyone messaging the client that it won't answer soon.
+
+pub struct Presence {
+    // "telnet" or "ssh"
+    pub transport: &'static str,
+    pub connected_at: DateTime<Local>,
+    // the last time the client typed something
+    pub last_active: Instant,
+    pub away: Option<String>,
+}
+
+impl Presence {
+    pub fn new(transport: &'static str) -> Self {
+        Presence {
+            transport,
+            connected_at: Local::now(),
+            last_active: Instant::now(),
+            away: None,
+        }
+    }
+}
+
+// tells everyone but `name` about it
+pub fn announce(clients: &Clients, name: &str, text: &str) {
+    for other in clients.keys().filter(|other| *other != name) {
+        send_notice(clients, other, text);
+    }
+}
+
+pub fn who(clients: &Clients, name: &str) {
+    let mut names: Vec<&String> = clients.keys().collect();
+    names.sort();
+
+    let mut list = format!("{} online:", names.len());
+    for other in names {
+        let presence = &clients[other].presence;
+        list.push_str(&format!(
+            "\n  {:<20} {:<6} since {}, idle {}",
+            other,
+            presence.transport,
+            presence.connected_at.format("%H:%M"),
+            format_duration(presence.last_active.elapsed())
+        ));
+        if let Some(reason) = &presence.away {
+            list.push_str(&format!(", away: {reason}"));
+        }
+    }
+    send_notice(clients, name, list);
+}
+
+pub fn away(clients: &mut Clients, name: &str, reason: Option<String>) {
+    let reason = reason.unwrap_or_else(|| "away".to_string());
+    if let Some(client) = clients.get_mut(name) {
+        client.presence.away = Some(reason.clone());
+    }
+    send_notice(
+        clients,
+        name,
+        format!("You are marked as away ({reason}), type /back when you return."),
+    );
+    announce(clients, name, &format!("{name} is away: {reason}"));
+}
+
+pub fn back(clients: &mut Clients, name: &str) {
+    let was_away = clients
+        .get_mut(name)
+        .and_then(|client| client.presence.away.take())
+        .is_some();
+    match was_away {
+        true => {
+            send_notice(clients, name, "Welcome back!");
+            announce(clients, name, &format!("{name} is back."));
+        }
+        false => send_notice(clients, name, "You are not away."),
+    }
+}
+
+// lets `from` know when a direct message went to someone who is away
+pub fn notify_if_away(clients: &Clients, from: &str, to: &str) {
+    if let Some(reason) = clients
+        .get(to)
+        .and_then(|client| client.presence.away.as_ref())
+    {
+        send_notice(clients, from, format!("{to} is away: {reason}"));
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m", seconds / 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::broker::{send_notice, Clients};
+
+// Flood protection with token buckets: every line a client sends takes a token,
+// tokens come back at a fixed rate up to a burst size.
+// Broadcasts (to everyone or to a room) reach many clients, so they have their own, stricter bucket,
+// and all clients from the same address share another one. A line needs a token from both.
+// Clients that keep hitting the limit get muted for a while.
+
+#[derive(Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+#[derive(Clone, Copy)]
+pub struct RateLimits {
+    // direct messages and every other command
+    pub direct: Limit,
+    pub broadcast: Limit,
+    pub per_ip: Limit,
+    // violations in a row before a client is muted
+    pub mute_after: u32,
+    pub mute_for: Duration,
+}
+
+#[derive(Clone, Copy)]
+pub enum Traffic {
+    Direct,
+    Broadcast,
+}
+
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.refilled_at = now;
+    }
+
+    fn has_token(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.limit.burst as f64
+    }
+}
+
+// the part of the limiting that belongs to a single client
+pub struct ClientLimiter {
+    direct: TokenBucket,
+    broadcast: TokenBucket,
+    violations: u32,
+    muted_until: Option<Instant>,
+}
+
+impl ClientLimiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        ClientLimiter {
+            direct: TokenBucket::new(limits.direct),
+            broadcast: TokenBucket::new(limits.broadcast),
+            violations: 0,
+            muted_until: None,
+        }
+    }
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    per_ip: HashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            per_ip: HashMap::new(),
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    // false if the line has to be dropped, the client is told why
+    pub fn allow(&mut self, clients: &mut Clients, name: &str, traffic: Traffic) -> bool {
+        let client = match clients.get_mut(name) {
+            Some(client) => client,
+            None => return false,
+        };
+        let now = Instant::now();
+        let limiter = &mut client.rate_limiter;
+
+        if let Some(muted_until) = limiter.muted_until {
+            if now < muted_until {
+                return false;
+            }
+            limiter.muted_until = None;
+            limiter.violations = 0;
+        }
+
+        // buckets of addresses nobody used for a while are full again, so they can go
+        self.per_ip.retain(|_, bucket| !bucket.is_full());
+        let mut ip_bucket = client.ip.map(|ip| {
+            self.per_ip
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::new(self.limits.per_ip))
+        });
+        let bucket = match traffic {
+            Traffic::Direct => &mut limiter.direct,
+            Traffic::Broadcast => &mut limiter.broadcast,
+        };
+        // a dropped line takes nothing, so f.e. too many broadcasts don't use up the address' tokens
+        let allowed =
+            bucket.has_token() && ip_bucket.as_mut().is_none_or(|bucket| bucket.has_token());
+        if allowed {
+            bucket.take();
+            if let Some(ip_bucket) = ip_bucket {
+                ip_bucket.take();
+            }
+            limiter.violations = 0;
+            return true;
+        }
+
+        limiter.violations += 1;
+        let warning = match limiter.violations >= self.limits.mute_after {
+            true => {
+                limiter.muted_until = Some(now + self.limits.mute_for);
+                format!(
+                    "You are muted for {} seconds because you kept sending too fast.",
+                    self.limits.mute_for.as_secs()
+                )
+            }
+            false => match traffic {
+                Traffic::Direct => "You are sending too fast, that message was dropped.",
+                Traffic::Broadcast => {
+                    "You are messaging everyone or a room too often, that message was dropped."
+                }
+            }
+            .to_string(),
+        };
+        send_notice(clients, name, warning);
+        false
+    }
+}
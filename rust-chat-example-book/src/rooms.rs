@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{send_notice, Clients};
+use crate::history::{Conversation, History};
+use crate::message::{ChatMessage, MessageKind, Outgoing};
+use crate::nickname;
+use crate::storage::Record;
+
+// Named rooms besides "all":
+// "/join games" creates the room if nobody is in it yet and makes it the current room,
+// so plain lines go there until the client joins another one or leaves it.
+// Empty rooms are removed unless they have a topic, and clients leave all their rooms
+// when they disconnect. Only the member who has been in a room the longest may set its topic,
+// so leaving and joining again puts a client at the back of the line.
+// Topics are stored, so rooms that have one are still there after a restart,
+// "/topic -" clears it and lets the room go once it is empty.
+
+#[derive(Default)]
+pub struct Room {
+    // in the order they joined
+    pub members: Vec<String>,
+    pub topic: Option<Topic>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Topic {
+    pub text: String,
+    pub set_by: String,
+}
+
+impl Room {
+    fn is_member(&self, name: &str) -> bool {
+        self.members.iter().any(|member| member == name)
+    }
+
+    fn is_abandoned(&self) -> bool {
+        self.members.is_empty() && self.topic.is_none()
+    }
+
+    fn may_change_topic(&self, name: &str) -> bool {
+        self.members.first().is_some_and(|first| first == name)
+    }
+}
+
+pub type Rooms = HashMap<String, Room>;
+
+// "#games" and "games" are the same room
+pub fn room_name(name: &str) -> &str {
+    name.strip_prefix('#').unwrap_or(name)
+}
+
+pub fn join(rooms: &mut Rooms, clients: &mut Clients, history: &History, name: &str, room: &str) {
+    // rooms follow the same rules as nicknames, which also keeps "all" free
+    let room = match nickname::validate(room_name(room)) {
+        Ok(room) => room,
+        Err(error) => return send_notice(clients, name, format!("{error}")),
+    };
+    set_current_room(clients, name, Some(room.clone()));
+
+    let joined = rooms.entry(room.clone()).or_default();
+    if joined.is_member(name) {
+        return send_notice(clients, name, format!("You are now talking in #{room}."));
+    }
+    joined.members.push(name.to_string());
+    let mut welcome = format!(
+        "You joined #{room} ({} online), your messages go there now.",
+        joined.members.len()
+    );
+    if let Some(topic) = &joined.topic {
+        welcome.push_str(&format!(
+            "\nTopic: {} (set by {})",
+            topic.text, topic.set_by
+        ));
+    }
+    announce(
+        rooms,
+        clients,
+        &room,
+        name,
+        &format!("{name} joined #{room}."),
+    );
+    send_notice(clients, name, welcome);
+    let conversation = Conversation::Room(room);
+    history.replay(clients, name, &conversation, history.config().replay);
+}
+
+// `room` may be left out to leave the current one
+pub fn leave(rooms: &mut Rooms, clients: &mut Clients, name: &str, room: Option<String>) {
+    let current_room = clients
+        .get(name)
+        .and_then(|client| client.current_room.clone());
+    let room = match room.or(current_room) {
+        Some(room) => room_name(&room).to_string(),
+        None => return send_notice(clients, name, "You are not in a room."),
+    };
+    if !remove_member(rooms, clients, &room, name) {
+        return send_notice(clients, name, format!("You are not in #{room}."));
+    }
+    send_notice(clients, name, format!("You left #{room}."));
+}
+
+// on disconnect, everyone is told "left the chat" already, so the rooms stay quiet
+pub fn leave_all(rooms: &mut Rooms, name: &str) {
+    for room in rooms.values_mut() {
+        room.members.retain(|member| member != name);
+    }
+    rooms.retain(|_, room| !room.is_abandoned());
+}
+
+pub fn list(rooms: &Rooms, clients: &Clients, name: &str) {
+    if rooms.is_empty() {
+        return send_notice(
+            clients,
+            name,
+            "There are no rooms yet, open one with /join <room>.",
+        );
+    }
+    let current_room = clients
+        .get(name)
+        .and_then(|client| client.current_room.as_ref());
+
+    let mut names: Vec<&String> = rooms.keys().collect();
+    names.sort();
+    let mut list = String::from("Rooms:");
+    for room in names {
+        let marker = match Some(room) == current_room {
+            true => " (current)",
+            false => "",
+        };
+        list.push_str(&format!(
+            "\n  #{room} - {} online{marker}",
+            rooms[room].members.len()
+        ));
+        if let Some(topic) = &rooms[room].topic {
+            list.push_str(&format!(": {}", topic.text));
+        }
+    }
+    send_notice(clients, name, list);
+}
+
+// shows the topic of the current room, or changes it if `text` is given, "-" clears it
+pub fn topic(
+    rooms: &mut Rooms,
+    clients: &Clients,
+    history: &mut History,
+    name: &str,
+    text: Option<String>,
+) {
+    let current_room = clients
+        .get(name)
+        .and_then(|client| client.current_room.clone());
+    let (room_name, room) = match current_room {
+        Some(room_name) => match rooms.get_mut(&room_name) {
+            Some(room) => (room_name, room),
+            None => return,
+        },
+        None => return send_notice(clients, name, "Join a room first, with /join <room>."),
+    };
+
+    let text = match text {
+        Some(text) => text,
+        None => {
+            let topic = match &room.topic {
+                Some(topic) => format!(
+                    "Topic of #{room_name}: {} (set by {})",
+                    topic.text, topic.set_by
+                ),
+                None => format!("#{room_name} has no topic yet."),
+            };
+            return send_notice(clients, name, topic);
+        }
+    };
+    if !room.may_change_topic(name) {
+        let first = room.members.first().map_or("", |first| first.as_str());
+        return send_notice(
+            clients,
+            name,
+            format!("Only {first} may change the topic of #{room_name}, they have been here the longest."),
+        );
+    }
+
+    if text == "-" {
+        if room.topic.take().is_none() {
+            return send_notice(clients, name, format!("#{room_name} has no topic yet."));
+        }
+        for member in &room.members {
+            send_notice(
+                clients,
+                member,
+                format!("{name} cleared the topic of #{room_name}."),
+            );
+        }
+        return history.persist(Record::TopicCleared { room: room_name });
+    }
+
+    for member in &room.members {
+        send_notice(
+            clients,
+            member,
+            format!("{name} changed the topic of #{room_name} to: {text}"),
+        );
+    }
+    let topic = Topic {
+        text,
+        set_by: name.to_string(),
+    };
+    room.topic = Some(topic.clone());
+    history.persist(Record::Topic {
+        room: room_name,
+        topic,
+    });
+}
+
+// for rooms from the store, they stay empty until someone joins,
+// `None` is a topic that was cleared later on
+pub fn restore_topic(rooms: &mut Rooms, room: String, topic: Option<Topic>) {
+    match topic {
+        Some(topic) => rooms.entry(room).or_default().topic = Some(topic),
+        None => {
+            rooms.remove(&room);
+        }
+    }
+}
+
+// what has to be kept when the store is compacted
+pub fn records(rooms: &Rooms) -> Vec<Record> {
+    rooms
+        .iter()
+        .filter_map(|(room, state)| {
+            let topic = state.topic.clone()?;
+            Some(Record::Topic {
+                room: room.clone(),
+                topic,
+            })
+        })
+        .collect()
+}
+
+// only members can talk in a room
+pub fn say(
+    rooms: &Rooms,
+    clients: &Clients,
+    history: &mut History,
+    name: &str,
+    room: &str,
+    text: &str,
+) {
+    let members = match rooms.get(room) {
+        Some(room) if room.is_member(name) => &room.members,
+        _ => return send_notice(clients, name, format!("You are not in #{room}.")),
+    };
+    let kind = MessageKind::Room {
+        room: room.to_string(),
+    };
+    let message = ChatMessage::new(name, kind, text);
+    history.record_room(room, &message);
+    let message = Outgoing::Chat(message);
+    for member in members.iter().filter(|member| *member != name) {
+        if let Some(client) = clients.get(member) {
+            client.deliver(&message);
+        }
+    }
+}
+
+// returns false if `name` wasn't a member
+fn remove_member(rooms: &mut Rooms, clients: &mut Clients, room: &str, name: &str) -> bool {
+    let removed = rooms.get_mut(room).is_some_and(|room| {
+        let before = room.members.len();
+        room.members.retain(|member| member != name);
+        room.members.len() < before
+    });
+    if !removed {
+        return false;
+    }
+
+    if rooms[room].is_abandoned() {
+        rooms.remove(room);
+    } else {
+        announce(rooms, clients, room, name, &format!("{name} left #{room}."));
+    }
+    if clients
+        .get(name)
+        .is_some_and(|client| client.current_room.as_deref() == Some(room))
+    {
+        set_current_room(clients, name, None);
+    }
+    true
+}
+
+// tells every member but `name`
+fn announce(rooms: &Rooms, clients: &Clients, room: &str, name: &str, text: &str) {
+    if let Some(room) = rooms.get(room) {
+        for member in room.members.iter().filter(|member| *member != name) {
+            send_notice(clients, member, text);
+        }
+    }
+}
+
+fn set_current_room(clients: &mut Clients, name: &str, room: Option<String>) {
+    if let Some(client) = clients.get_mut(name) {
+        client.current_room = room;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::tests::TestClients;
+    use crate::history::HistoryConfig;
+    use crate::storage::{MemoryStore, Store};
+
+    // without clients the notices go nowhere and only the rooms change
+    struct Chat {
+        rooms: Rooms,
+        clients: Clients,
+        history: History,
+        store: MemoryStore,
+    }
+
+    impl Chat {
+        fn new() -> Self {
+            let config = HistoryConfig { size: 0, replay: 0 };
+            let store = MemoryStore::default();
+            Chat {
+                rooms: Rooms::new(),
+                clients: Clients::new(),
+                history: History::new(config, Box::new(store.clone())),
+                store,
+            }
+        }
+
+        fn join(&mut self, name: &str) {
+            join(
+                &mut self.rooms,
+                &mut self.clients,
+                &self.history,
+                name,
+                "games",
+            );
+        }
+
+        fn leave(&mut self, name: &str) {
+            remove_member(&mut self.rooms, &mut self.clients, "games", name);
+        }
+
+        fn topic(&mut self, name: &str, text: &str) {
+            topic(
+                &mut self.rooms,
+                &self.clients,
+                &mut self.history,
+                name,
+                Some(text.to_string()),
+            );
+        }
+
+        fn may_change_topic(&self, name: &str) -> bool {
+            self.rooms["games"].may_change_topic(name)
+        }
+    }
+
+    #[test]
+    fn creator_may_change_the_topic() {
+        let mut chat = Chat::new();
+        chat.join("alice");
+        chat.join("bob");
+        assert!(chat.may_change_topic("alice"));
+        assert!(!chat.may_change_topic("bob"));
+    }
+
+    #[test]
+    fn longest_present_member_takes_over() {
+        let mut chat = Chat::new();
+        chat.join("alice");
+        chat.join("bob");
+        chat.join("carol");
+        chat.leave("alice");
+        assert!(chat.may_change_topic("bob"));
+        assert!(!chat.may_change_topic("carol"));
+        chat.leave("bob");
+        assert!(chat.may_change_topic("carol"));
+    }
+
+    #[test]
+    fn joining_again_goes_to_the_back_of_the_line() {
+        let mut chat = Chat::new();
+        chat.join("alice");
+        chat.join("bob");
+        chat.join("carol");
+        chat.leave("bob");
+        chat.join("bob");
+        chat.leave("alice");
+        assert!(chat.may_change_topic("carol"));
+        assert!(!chat.may_change_topic("bob"));
+    }
+
+    #[test]
+    fn restored_topic_goes_to_the_first_one_back() {
+        let mut chat = Chat::new();
+        let topic = Topic {
+            text: "chess".to_string(),
+            set_by: "alice".to_string(),
+        };
+        restore_topic(&mut chat.rooms, "games".to_string(), Some(topic));
+        assert!(!chat.may_change_topic("alice"));
+
+        chat.join("bob");
+        chat.join("alice");
+        assert!(chat.may_change_topic("bob"));
+        assert!(!chat.may_change_topic("alice"));
+    }
+
+    #[tokio::test]
+    async fn cleared_topic_lets_the_room_go() {
+        let mut chat = Chat::new();
+        chat.clients = TestClients::new(&["alice"]).clients;
+        chat.join("alice");
+        chat.topic("alice", "chess");
+        chat.leave("alice");
+        assert!(chat.rooms.contains_key("games"));
+
+        chat.join("alice");
+        chat.topic("alice", "-");
+        assert!(chat.rooms["games"].topic.is_none());
+        chat.leave("alice");
+        assert!(!chat.rooms.contains_key("games"));
+
+        // and it stays gone after a restart
+        chat.history.close().await;
+        let mut rooms = Rooms::new();
+        for record in chat.store.load().unwrap() {
+            match record {
+                Record::Topic { room, topic } => restore_topic(&mut rooms, room, Some(topic)),
+                Record::TopicCleared { room } => restore_topic(&mut rooms, room, None),
+                _ => (),
+            }
+        }
+        assert!(rooms.is_empty());
+    }
+}
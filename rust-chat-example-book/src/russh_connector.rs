@@ -1,3 +1,4 @@
+use anyhow::anyhow;
 use async_trait::async_trait;
 use russh::keys::*;
 use russh::server::{Msg, Server as _, Session};
@@ -7,24 +8,96 @@ use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::net::IpAddr;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::{TcpListener, ToSocketAddrs};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
 
+use crate::broker::{client_names, handle_input, join, Event, Join, Transport};
+use crate::input_buffer::ChannelInput;
+use crate::line_editor::LineEditor;
+use crate::message::OutputFormat;
 use crate::utils::BoxedResult;
 
-pub async fn start_russh_server(addr: impl ToSocketAddrs) -> BoxedResult<()> {
+// how long clients get to close their sessions when the server stops
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+// the chat channel of every open session, by the id of the session's handler,
+// only locked to insert, remove or copy entries and never across an await
+type Sessions = Arc<Mutex<HashMap<usize, (server::Handle, ChannelId)>>>;
+
+// `stop_accepting` turns new clients away, `shutdown_notification` closes the sessions
+pub async fn start_russh_server(
+    addr: impl ToSocketAddrs,
+    broker_sender: UnboundedSender<Event>,
+    stop_accepting: Arc<Notify>,
+    shutdown_notification: Arc<Notify>,
+    motd: Arc<str>,
+) -> BoxedResult<()> {
     let mut sh = Server {
-        clients: Arc::new(Mutex::new(HashMap::new())),
+        broker_sender,
+        motd,
         id: 0,
-        client_connections: Arc::new(Mutex::new(HashMap::new())),
+        name: String::new(),
+        peer_ip: None,
+        chat_channel: None,
+        joined: false,
+        inputs: HashMap::new(),
+        sessions: Arc::new(Mutex::new(HashMap::new())),
     };
     let listener = TcpListener::bind(addr).await?;
-    sh.connect(listener).await?;
+    println!("SSH server listening on {}", listener.local_addr()?);
+
+    // created first, so a shutdown right after the listener stopped is not missed
+    let shutdown = shutdown_notification.notified();
+    // dropping `connect` stops accepting, the sessions run on their own tasks
+    tokio::select! {
+        result = sh.connect(listener) => result?,
+        _ = stop_accepting.notified() => (),
+    }
+    shutdown.await;
+    close_sessions(&sh.sessions).await;
+    println!("SSH server stopped.");
     Ok(())
 }
 
+// the broker already said goodbye to every client, so their channels can be closed
+async fn close_sessions(sessions: &Sessions) {
+    let open: Vec<_> = sessions.lock().unwrap().values().cloned().collect();
+    for (handle, channel) in &open {
+        let _ = handle.eof(*channel).await;
+        let _ = handle.close(*channel).await;
+    }
+
+    // every handler removes itself once its client is gone
+    let closed = tokio::time::timeout(SHUTDOWN_DEADLINE, async {
+        while !sessions.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+    if closed.await.is_ok() {
+        return;
+    }
+
+    let open: Vec<_> = sessions.lock().unwrap().values().cloned().collect();
+    println!(
+        "Disconnecting {} ssh sessions that didn't close in time.",
+        open.len()
+    );
+    for (handle, _) in open {
+        let _ = handle
+            .disconnect(
+                Disconnect::ByApplication,
+                "The server is shutting down.".to_string(),
+                "en".to_string(),
+            )
+            .await;
+    }
+}
+
 pub fn check_public_key<P: AsRef<Path>>(
     path: P,
     client_public_key: &key::PublicKey,
@@ -56,11 +129,59 @@ pub fn check_public_key<P: AsRef<Path>>(
     Ok(false)
 }
 
+fn ssh_lines(text: &str) -> String {
+    text.replace('\n', "\r\n")
+}
+
+// The broker gives every client its own queue and a writer task that owns this transport
+// (see `receive_messages_on_loop`), so a client that reads slowly only ever delays itself
+// and no lock is held while `Handle::data` waits.
+struct SshTransport {
+    handle: russh::server::Handle,
+    channel: ChannelId,
+    peer_ip: Option<IpAddr>,
+}
+
+#[async_trait]
+impl Transport for SshTransport {
+    async fn send(&mut self, message: &str, _format: OutputFormat) -> BoxedResult<()> {
+        let data = format!("{}\r\n", ssh_lines(message));
+        self.handle
+            .data(self.channel, CryptoVec::from(data))
+            .await
+            .map_err(|_| anyhow!("Could not send data on channel {:?}", self.channel))
+    }
+
+    fn kind(&self) -> &'static str {
+        "ssh"
+    }
+
+    // closing the channel lets the handler clean up, see `channel_close`
+    async fn close(&mut self) {
+        let _ = self.handle.close(self.channel).await;
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_ip
+    }
+}
+
 #[derive(Clone)]
 struct Server {
-    clients: Arc<Mutex<HashMap<(usize, ChannelId), russh::server::Handle>>>,
+    broker_sender: UnboundedSender<Event>,
+    // shown as the auth banner, and again once the chat channel is open
+    motd: Arc<str>,
     id: usize,
-    client_connections: Arc<Mutex<HashMap<usize, usize>>>,
+    // the ssh user name is used as the chat name, so ssh and telnet users share one namespace
+    name: String,
+    peer_ip: Option<IpAddr>,
+    // the channel the client chats on, a session only gets one
+    chat_channel: Option<ChannelId>,
+    // false until the broker accepted the name, until then every line is another try
+    joined: bool,
+    // this handler only serves one client, so these are the buffers of each of its channels
+    inputs: HashMap<ChannelId, ChannelInput>,
+    sessions: Sessions,
 }
 
 impl Server {
@@ -70,6 +191,10 @@ impl Server {
             auth_rejection_time: std::time::Duration::from_secs(3),
             auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
             keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
+            // russh wants a &'static str, the config is only built once per server
+            auth_banner: Some(Box::leak(
+                format!("{}\r\n", ssh_lines(&self.motd)).into_boxed_str(),
+            )),
             ..Default::default()
         };
 
@@ -78,19 +203,73 @@ impl Server {
         Ok(())
     }
 
-    async fn post(&mut self, receiver_id: usize, data: CryptoVec) {
-        let clients = self.clients.lock().await;
-        let receiver_client = clients.iter().find(|((id, _), _)| id == &receiver_id);
-        if let Some(((_, channel), s)) = receiver_client {
-            let _ = s.data(*channel, data).await;
+    async fn try_join(&mut self, channel: ChannelId, session: &mut Session) -> BoxedResult<()> {
+        let transport = SshTransport {
+            handle: session.handle(),
+            channel,
+            peer_ip: self.peer_ip,
+        };
+        let message = match join(&self.broker_sender, &self.name, Box::new(transport)).await? {
+            Join::Accepted { name } => {
+                self.name = name;
+                self.joined = true;
+                println!("{} joined.", self.name);
+                format!(
+                    "You are in the chat as {}, type /help to see the commands.\r\n",
+                    self.name
+                )
+            }
+            Join::Rejected { reason } => format!("{reason}\r\nInput your name: "),
+        };
+        session.data(channel, CryptoVec::from(message));
+        Ok(())
+    }
+
+    async fn handle_line(
+        &mut self,
+        channel: ChannelId,
+        line: &str,
+        session: &mut Session,
+    ) -> BoxedResult<()> {
+        if !self.joined {
+            self.name = line.to_string();
+            return self.try_join(channel, session).await;
+        }
+        if !handle_input(&self.broker_sender, &self.name, line)? {
+            // messages sent to client here cannot be received on client :/
+            session.close(channel);
+            self.leave();
+        }
+        Ok(())
+    }
+
+    // called for every way a client can go, but the broker only hears about it once
+    fn leave(&mut self) {
+        if self.joined {
+            self.joined = false;
+            let _ = self.broker_sender.send(Event::Disconnect {
+                name: self.name.clone(),
+            });
+        }
+    }
+}
+
+// the session is gone, f.e. because the client's connection dropped
+impl Drop for Server {
+    fn drop(&mut self) {
+        if self.chat_channel.is_some() {
+            self.sessions.lock().unwrap().remove(&self.id);
         }
+        self.leave();
     }
 }
 
 impl server::Server for Server {
     type Handler = Self;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
-        let s = self.clone();
+    fn new_client(&mut self, addr: Option<std::net::SocketAddr>) -> Self {
+        let mut s = self.clone();
+        s.name = format!("client_{}", s.id);
+        s.peer_ip = addr.map(|addr| addr.ip());
         self.id += 1;
         println!("Client joined. New client receives the id {}", { s.id });
         s
@@ -105,22 +284,44 @@ impl server::Handler for Server {
         channel: Channel<Msg>,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        let mut clients = self.clients.lock().await;
         let channel_id = channel.id().to_owned();
-
-        clients.insert((self.id, channel_id), session.handle());
-
-        let _ = session
-            .handle()
-            .data(
-                channel_id,
-                CryptoVec::from("The connection to the server was established!\r\n".to_string()),
-            )
-            .await;
+        if self.chat_channel.is_some() {
+            return Ok(false);
+        }
+        self.chat_channel = Some(channel_id);
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(self.id, (session.handle(), channel_id));
+        self.inputs.insert(channel_id, ChannelInput::default());
+
+        // not every client shows the auth banner
+        session.data(
+            channel_id,
+            CryptoVec::from(format!("{}\r\n", ssh_lines(&self.motd))),
+        );
+        self.try_join(channel_id, session).await?;
 
         Ok(true)
     }
 
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        _: &str,
+        _: u32,
+        _: u32,
+        _: u32,
+        _: u32,
+        _: &[(Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inputs
+            .insert(channel, ChannelInput::Editor(LineEditor::new()));
+        session.channel_success(channel);
+        Ok(())
+    }
+
     async fn auth_publickey_offered(
         &mut self,
         _: &str,
@@ -141,9 +342,10 @@ impl server::Handler for Server {
 
     async fn auth_publickey(
         &mut self,
-        _: &str,
+        user: &str,
         _: &key::PublicKey,
     ) -> Result<server::Auth, Self::Error> {
+        self.name = user.to_string();
         // println!(
         //     "New client authorized! {}",
         //     String::from_utf16_lossy(&key.public_key_bytes())
@@ -153,90 +355,53 @@ impl server::Handler for Server {
 
     // N9��;b▬‼��↓☻L�]9�茾�M�aox+dң��ʘ�↔-ǜ?��Q��☺�r�3�§3�c_����Vm♀�s§u�#��꙱♂���M�Weh��� ���u0}�☺2����O;[C�↕=xU♫���+�B��OIn"]O.◄�vW�d�↔¶���hO�\��$�2Р�)5tS�+��s↨�M[☺;H��▬♫n▼�S�→O��▲��T�↨*�>8d��,9�A&|��\�^��▼䶎D*�X↓ɜ[�����‼`{~-����xQ��TkGC���♣�o��♦�e�8S���►򿭑�% ‼&☻N1u♀Y↓7+�Z�N��y7��4�U�h�-�"8{h8�ӌ����Q��[�
 
-    async fn data(
+    async fn channel_eof(
         &mut self,
         channel: ChannelId,
-        data: &[u8],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        // TODO: on receiver client, display which sender client the message came from
-
-        // TODO: create separate functions for what happens in the match statement
-        // TODO: create function that takes in receiver_channel_id and message_string and sends the message
-
-        // TODO: create enum for the commands
-        // TODO: create enum for messages that the client may receive on unexpected input
-        //              f.e. empty input, no command, no receiver id argument, no message
-
-        // TODO: clean disconnect on ctrl + c in server terminal
-        //          -> clients should receive a notification about it
-        //          -> potentially, clients should also be shut down
-        // TODO: clean disconnect on ctrl + c in client terminals
-        //          -> server should receive feedback about it and delete this client from its memory
-
-        let string = String::from_utf8_lossy(data);
-        let string = string.trim();
-
-        let split_input = string.split(' ');
-        let input_words: Vec<&str> = split_input.collect();
+        // f.e. ctrl + d in a client without a pty
+        session.close(channel);
+        self.leave();
+        Ok(())
+    }
 
-        if input_words.len() == 0 {
-            println!("Empty input from client id {}", &self.id);
-            return Ok(());
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inputs.remove(&channel);
+        if self.chat_channel == Some(channel) {
+            self.chat_channel = None;
+            self.sessions.lock().unwrap().remove(&self.id);
+            self.leave();
         }
+        Ok(())
+    }
 
-        // TODO:    get saved client connections
-        // let mut client_connections = self.client_connections.lock().await;
-
-        match input_words[0] {
-            "/message" => {
-                if input_words.len() < 2 {
-                    let _ = session
-                        .handle()
-                        .data(
-                            channel,
-                            CryptoVec::from(
-                                "Input must include the receiver id, then message".to_string(),
-                            ),
-                        )
-                        .await;
-                    return Ok(());
-                }
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let is_editor = self
+            .inputs
+            .get(&channel)
+            .is_some_and(ChannelInput::is_editor);
+        let names = match is_editor && data.contains(&b'\t') {
+            true => client_names(&self.broker_sender).await,
+            false => Vec::new(),
+        };
 
-                let first_argument = &input_words[1];
-                if let Ok(receiver_id) = first_argument.parse::<usize>() {
-                    let message = input_words[2..].join(" ");
-                    let _ = self.post(receiver_id, CryptoVec::from(message)).await;
-                }
-            }
-            "/clients" => {
-                let clients = self.clients.lock().await;
-                let channel_ids: Vec<String> =
-                    clients.keys().map(|(id, _)| id.to_string()).collect();
-                let data = format!(
-                    "\r\r\nFollowing client ids are available to be connected to: {}\r\n",
-                    channel_ids.join(", ")
-                );
-                let data = format!("{data}Your client id is {}\r\r\n", self.id);
-
-                let _ = session.handle().data(channel, CryptoVec::from(data)).await;
-            }
-            "/quit" => {
-                // messages sent to client here cannot be received on client :/
+        let input = self.inputs.entry(channel).or_default().push(data, &names);
+        if !input.echo.is_empty() {
+            session.data(channel, CryptoVec::from(input.echo));
+        }
 
-                self.clients.lock().await.remove(&(self.id, channel));
-                session.close(channel);
-            }
-            _ => {
-                // TODO: if no command was given, send message to last communicated to client
-
-                // let current_client_connection =
-                //     client_connections.iter().find(|(id, _)| id == &self.id);
-                // if let Some((_, receiver_id)) = receiver_client {
-                //     let message = input_words[0..].join(" ");
-                //     let _ = self.post(receiver_id, CryptoVec::from(message)).await;
-                // }
-            }
+        for line in input.lines {
+            self.handle_line(channel, &line, session).await?;
         }
 
         Ok(())
@@ -3,11 +3,13 @@ use async_trait::async_trait;
 use dotenv::dotenv;
 use russh::{
     client::{self, Session},
-    ChannelId,
+    Channel, ChannelId, ChannelMsg, Disconnect,
 };
 use russh_keys::{key, load_secret_key};
-use std::{env, fs::File, io::Read, sync::Arc};
-use tokio::io::{stdin, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::{env, fs::File, future::Future, io::Read, sync::Arc, time::Duration};
+use tokio::io::{stdin, AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::signal::ctrl_c;
+use tokio::time::{sleep_until, Instant};
 
 mod utils;
 use utils::BoxedResult;
@@ -43,18 +45,27 @@ pub(crate) async fn main() -> BoxedResult<()> {
 }
 
 // Read data from server ->     go to Client implementation -> data()
-// Send data to server ->       go to tokio::spawn -> reader.read_line match -> stream.write_all
+// Send data to server ->       go to the loop in start_ssh_driver -> channel.data
+// Leave with /quit, ctrl + d or ctrl + c, the driver exits with
+//      0   after leaving like that
+//      1   on errors, f.e. when the login failed
+//      2   when the server closed the connection, f.e. because it was shut down
 
-// 1. Running client, client gets saved with its unique id
-// 2. User types command "/connect 3"
-// 3. Server interprets the message as command and saves a connection to client with id 3
-//      ->  only if client with id 3 isn't already connected,
-//          otherwise user receives "Cannot connect to 3 at the moment" from server
+// how long to wait for the server to close the channel after saying goodbye
+const QUIT_TIMEOUT: Duration = Duration::from_secs(3);
+const EXIT_CLOSED_BY_SERVER: i32 = 2;
+
+// 1. Running client, client gets saved with its ssh user name
+// 2. User types command "/connect bob"
+// 3. Server interprets the message as command and asks bob to "/accept" or "/decline"
+//      ->  only if bob isn't already connected,
+//          otherwise user receives "Cannot connect to bob at the moment" from server
 // 4. User types message
-// 5. Server receives message, checks that user's client is already connected to client 3
-// 6. Client 3 receives the message.
+// 5. Server receives message, checks that user's client is connected to bob
+// 6. bob receives the message.
+// (see pairing.rs on the server)
 
-// type command "/clients" and see a list of available client ids
+// type command "/clients" and see a list of available client names
 
 pub async fn start_ssh_driver(
     user: &str,
@@ -74,35 +85,104 @@ pub async fn start_ssh_driver(
         .await?;
     // println!("auth_res: {}", auth_res);
 
-    let channel = session.channel_open_session().await?;
+    let mut channel = session.channel_open_session().await?;
 
     // let _ = channel
     //     .data("Hello from client!".to_string().as_bytes())
     //     .await;
 
-    let mut stream = channel.into_stream();
-    let stdin = stdin();
-    let mut reader = BufReader::new(stdin);
+    let ending = chat(BufReader::new(stdin()), ctrl_c(), &mut channel).await?;
 
-    let mut line_in = String::new();
+    let _ = channel.close().await;
+    let _ = session
+        .disconnect(Disconnect::ByApplication, "The user left the chat.", "en")
+        .await;
 
-    match tokio::spawn(async move {
-        loop {
-            match reader.read_line(&mut line_in).await {
-                Err(_) => break,
-                _ => {
-                    stream.write_all(&line_in.as_bytes()).await?;
-                    line_in = String::new();
-                }
+    if let Ending::ClosedByServer = ending {
+        eprintln!("The server closed the connection.");
+        std::process::exit(EXIT_CLOSED_BY_SERVER);
+    }
+    Ok(())
+}
+
+// the parts of the ssh channel `chat` needs, so it can be tested without a server
+#[async_trait]
+trait Connection {
+    async fn send(&mut self, data: &[u8]) -> BoxedResult<()>;
+    async fn eof(&mut self) -> BoxedResult<()>;
+    // waits until the server closes the channel, has to be safe to cancel
+    async fn closed(&mut self);
+}
+
+#[async_trait]
+impl Connection for Channel<client::Msg> {
+    async fn send(&mut self, data: &[u8]) -> BoxedResult<()> {
+        self.data(data).await?;
+        Ok(())
+    }
+
+    async fn eof(&mut self) -> BoxedResult<()> {
+        Channel::eof(self).await?;
+        Ok(())
+    }
+
+    async fn closed(&mut self) {
+        while let Some(message) = self.wait().await {
+            if let ChannelMsg::Close = message {
+                return;
             }
         }
-        anyhow::Ok::<()>(())
-    })
-    .await?
-    {
-        _ => {}
     }
-    return Ok(());
+}
+
+enum Ending {
+    // with /quit, ctrl + d or `interrupt`
+    Quit,
+    ClosedByServer,
+}
+
+// sends every line of `input` to the server until the user or the server ends the chat
+async fn chat(
+    input: impl AsyncBufRead + Unpin,
+    interrupt: impl Future<Output = std::io::Result<()>>,
+    connection: &mut impl Connection,
+) -> BoxedResult<Ending> {
+    let mut lines = input.lines();
+    tokio::pin!(interrupt);
+    // set once the user wants to leave, the server answers by closing the channel
+    let mut quit_deadline: Option<Instant> = None;
+
+    loop {
+        tokio::select! {
+            line = lines.next_line(), if quit_deadline.is_none() => match line? {
+                Some(line) => {
+                    connection.send(format!("{line}\n").as_bytes()).await?;
+                    if line.trim() == "/quit" {
+                        connection.eof().await?;
+                        quit_deadline = Some(Instant::now() + QUIT_TIMEOUT);
+                    }
+                }
+                // ctrl + d
+                None => quit_deadline = Some(quit(connection).await?),
+            },
+            _ = &mut interrupt, if quit_deadline.is_none() => quit_deadline = Some(quit(connection).await?),
+            _ = connection.closed() => break,
+            // the expression is evaluated even while the branch is disabled, so it must not unwrap
+            _ = sleep_until(quit_deadline.unwrap_or_else(Instant::now)), if quit_deadline.is_some() => break,
+        }
+    }
+
+    Ok(match quit_deadline {
+        Some(_) => Ending::Quit,
+        None => Ending::ClosedByServer,
+    })
+}
+
+// says goodbye the same way typing /quit does, returns until when to wait for the server
+async fn quit(connection: &mut impl Connection) -> BoxedResult<Instant> {
+    connection.send(b"/quit\n").await?;
+    connection.eof().await?;
+    Ok(Instant::now() + QUIT_TIMEOUT)
 }
 
 struct Client {}
@@ -130,3 +210,99 @@ impl client::Handler for Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct TestConnection {
+        sent: Vec<u8>,
+        eof: bool,
+        // the server closes the channel once it got the eof
+        closes_after_eof: bool,
+        closed_by_server: bool,
+    }
+
+    #[async_trait]
+    impl Connection for TestConnection {
+        async fn send(&mut self, data: &[u8]) -> BoxedResult<()> {
+            self.sent.extend(data);
+            Ok(())
+        }
+
+        async fn eof(&mut self) -> BoxedResult<()> {
+            self.eof = true;
+            Ok(())
+        }
+
+        async fn closed(&mut self) {
+            if !(self.closed_by_server || self.eof && self.closes_after_eof) {
+                pending::<()>().await;
+            }
+        }
+    }
+
+    fn never() -> impl Future<Output = std::io::Result<()>> {
+        pending()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quit_waits_for_the_server() {
+        let mut connection = TestConnection {
+            closes_after_eof: true,
+            ..TestConnection::default()
+        };
+        let ending = chat(&b"hello\n/quit\nnot sent\n"[..], never(), &mut connection).await;
+        assert!(matches!(ending.unwrap(), Ending::Quit));
+        assert_eq!(connection.sent, b"hello\n/quit\n");
+        assert!(connection.eof);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quit_gives_up_on_a_silent_server() {
+        let mut connection = TestConnection::default();
+        let started = Instant::now();
+        let ending = chat(&b"/quit\n"[..], never(), &mut connection).await;
+        assert!(matches!(ending.unwrap(), Ending::Quit));
+        assert_eq!(started.elapsed(), QUIT_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn end_of_input_quits() {
+        let mut connection = TestConnection::default();
+        let ending = chat(&b"hello"[..], never(), &mut connection).await;
+        assert!(matches!(ending.unwrap(), Ending::Quit));
+        assert_eq!(connection.sent, b"hello\n/quit\n");
+        assert!(connection.eof);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interrupt_quits() {
+        // the other end stays open, so there is no input and no end of it either
+        let (_keyboard, input) = duplex(64);
+        let mut connection = TestConnection {
+            closes_after_eof: true,
+            ..TestConnection::default()
+        };
+        let ending = chat(BufReader::new(input), async { Ok(()) }, &mut connection).await;
+        assert!(matches!(ending.unwrap(), Ending::Quit));
+        assert_eq!(connection.sent, b"/quit\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn server_closes_the_channel() {
+        let (_keyboard, input) = duplex(64);
+        let mut connection = TestConnection {
+            closed_by_server: true,
+            ..TestConnection::default()
+        };
+        let ending = chat(BufReader::new(input), never(), &mut connection).await;
+        assert!(matches!(ending.unwrap(), Ending::ClosedByServer));
+        assert!(connection.sent.is_empty());
+    }
+}
@@ -0,0 +1,476 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::message::ChatMessage;
+use crate::rooms::Topic;
+use crate::utils::BoxedResult;
+
+// What the broker remembers across restarts, as a list of `Record`s.
+// New records are only ever appended, from time to time everything that is still
+// needed is written to a snapshot and the records before it are thrown away.
+// `JsonLinesStore` keeps them on disk, `MemoryStore` forgets them when the server stops.
+// Either runs on a `StoreThread`, so the broker never waits for the disk.
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    RoomMessage { room: String, message: ChatMessage },
+    DirectMessage { to: String, message: ChatMessage },
+    // rooms with a topic stay around when everyone left
+    Topic { room: String, topic: Topic },
+    TopicCleared { room: String },
+    // someone joined for the first time, so messages to them can wait until they are back
+    KnownUser { name: String },
+    OfflineMessage { to: String, message: ChatMessage },
+    // everything waiting for `to` was handed over
+    OfflineDelivered { to: String },
+}
+
+pub trait Store: Send {
+    // everything since the last compaction, oldest first
+    fn load(&mut self) -> BoxedResult<Vec<Record>>;
+    fn append(&mut self, record: &Record) -> BoxedResult<()>;
+    // true once enough was appended that `compact` is worth it
+    fn needs_compaction(&self) -> bool;
+    // replaces everything stored with `records`
+    fn compact(&mut self, records: &[Record]) -> BoxedResult<()>;
+}
+
+// clones share their records, so what one stored can be loaded by another
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    records: Arc<Mutex<Vec<Record>>>,
+}
+
+impl Store for MemoryStore {
+    fn load(&mut self) -> BoxedResult<Vec<Record>> {
+        Ok(self.records.lock().unwrap().clone())
+    }
+
+    fn append(&mut self, record: &Record) -> BoxedResult<()> {
+        self.records.lock().unwrap().push(record.clone());
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        false
+    }
+
+    fn compact(&mut self, records: &[Record]) -> BoxedResult<()> {
+        *self.records.lock().unwrap() = records.to_vec();
+        Ok(())
+    }
+}
+
+enum Job {
+    Load(oneshot::Sender<BoxedResult<Vec<Record>>>),
+    Append(Record),
+    Compact(Vec<Record>),
+}
+
+// Owns a `Store` on a thread of its own and works through what the broker asks for in order.
+// Appending and compacting don't wait for the thread, failures are only printed:
+// a failing store doesn't stop the chat, it just won't be remembered.
+pub struct StoreThread {
+    jobs: mpsc::Sender<Job>,
+    // set by the thread once the store asks for it, and taken by `needs_compaction`
+    compaction_due: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl StoreThread {
+    pub fn spawn(mut store: Box<dyn Store>) -> Self {
+        let (jobs, receiver) = mpsc::channel();
+        let compaction_due = Arc::new(AtomicBool::new(false));
+        let due = compaction_due.clone();
+        let thread = thread::spawn(move || {
+            // asked for but not done yet, so it is asked for only once
+            let mut requested = false;
+            for job in receiver {
+                match job {
+                    Job::Load(reply) => {
+                        let _ = reply.send(store.load());
+                    }
+                    Job::Append(record) => {
+                        if let Err(e) = store.append(&record) {
+                            eprintln!("Could not store a record: {e}");
+                        }
+                    }
+                    Job::Compact(records) => {
+                        if let Err(e) = store.compact(&records) {
+                            eprintln!("Could not compact the stored messages: {e}");
+                        }
+                        requested = false;
+                    }
+                }
+                if !requested && store.needs_compaction() {
+                    requested = true;
+                    due.store(true, Ordering::Relaxed);
+                }
+            }
+        });
+        StoreThread {
+            jobs,
+            compaction_due,
+            thread,
+        }
+    }
+
+    // everything since the last compaction, oldest first
+    pub async fn load(&self) -> BoxedResult<Vec<Record>> {
+        let (reply, loaded) = oneshot::channel();
+        self.send(Job::Load(reply));
+        loaded.await?
+    }
+
+    pub fn append(&self, record: Record) {
+        self.send(Job::Append(record));
+    }
+
+    // true once, after the store asked for `compact`
+    pub fn needs_compaction(&self) -> bool {
+        self.compaction_due.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn compact(&self, records: Vec<Record>) {
+        self.send(Job::Compact(records));
+    }
+
+    // waits until everything asked for so far is done
+    pub async fn close(self) {
+        drop(self.jobs);
+        let thread = self.thread;
+        let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+    }
+
+    fn send(&self, job: Job) {
+        // only fails if the thread panicked, which it already printed
+        let _ = self.jobs.send(job);
+    }
+}
+
+// One json object per line, in numbered files in `dir`:
+//      segment-000007.jsonl    appended to until it is `segment_bytes` big, then the next one is started
+//      snapshot-000006.jsonl   everything up to and including segment 6, written by `compact`
+// A snapshot is only renamed into place once it is complete,
+// so a crash at any point leaves either the old or the new state behind, never a mix.
+pub struct JsonLinesStore {
+    dir: PathBuf,
+    segment_bytes: u64,
+    // segments since the last snapshot before compaction is due
+    compact_after: usize,
+    segment: u64,
+    file: File,
+    written: u64,
+    segments_since_snapshot: usize,
+}
+
+impl JsonLinesStore {
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        segment_bytes: u64,
+        compact_after: usize,
+    ) -> BoxedResult<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        // left over from a compaction that didn't finish
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let snapshot = numbered_files(&dir, "snapshot")?.pop().unwrap_or(0);
+        let segments: Vec<u64> = numbered_files(&dir, "segment")?
+            .into_iter()
+            .filter(|segment| *segment > snapshot)
+            .collect();
+        let segment = segments.last().copied().unwrap_or(snapshot + 1);
+        let mut file = open_segment(&dir, segment)?;
+        let written = file.metadata()?.len();
+        // so the next record doesn't end up on the same line as a cut off one
+        if written > 0 && !fs::read(file_path(&dir, "segment", segment))?.ends_with(b"\n") {
+            file.write_all(b"\n")?;
+        }
+
+        Ok(JsonLinesStore {
+            dir,
+            segment_bytes,
+            compact_after,
+            segment,
+            file,
+            written,
+            segments_since_snapshot: segments.len(),
+        })
+    }
+
+    fn rotate(&mut self) -> BoxedResult<()> {
+        self.segment += 1;
+        self.file = open_segment(&self.dir, self.segment)?;
+        self.written = 0;
+        self.segments_since_snapshot += 1;
+        Ok(())
+    }
+}
+
+impl Store for JsonLinesStore {
+    fn load(&mut self) -> BoxedResult<Vec<Record>> {
+        let snapshot = numbered_files(&self.dir, "snapshot")?.pop();
+        let mut paths = Vec::new();
+        if let Some(snapshot) = snapshot {
+            paths.push(file_path(&self.dir, "snapshot", snapshot));
+        }
+        for segment in numbered_files(&self.dir, "segment")? {
+            if segment > snapshot.unwrap_or(0) {
+                paths.push(file_path(&self.dir, "segment", segment));
+            }
+        }
+
+        let mut records = Vec::new();
+        for path in paths {
+            for (number, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+                // the last line may be cut off if the server crashed while writing it
+                match serde_json::from_str(&line?) {
+                    Ok(record) => records.push(record),
+                    Err(e) => eprintln!("Skipped line {} of {path:?}: {e}", number + 1),
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    fn append(&mut self, record: &Record) -> BoxedResult<()> {
+        let line = serde_json::to_string(record)? + "\n";
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        if self.written >= self.segment_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.segments_since_snapshot >= self.compact_after
+    }
+
+    fn compact(&mut self, records: &[Record]) -> BoxedResult<()> {
+        // `records` cover everything up to now, so new records go to a new segment
+        let through = self.segment;
+        self.rotate()?;
+
+        let snapshot = file_path(&self.dir, "snapshot", through);
+        let temporary = snapshot.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        for record in records {
+            file.write_all((serde_json::to_string(record)? + "\n").as_bytes())?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, &snapshot)?;
+
+        for segment in numbered_files(&self.dir, "segment")? {
+            if segment <= through {
+                fs::remove_file(file_path(&self.dir, "segment", segment))?;
+            }
+        }
+        for old in numbered_files(&self.dir, "snapshot")? {
+            if old < through {
+                fs::remove_file(file_path(&self.dir, "snapshot", old))?;
+            }
+        }
+        self.segments_since_snapshot = 0;
+        Ok(())
+    }
+}
+
+fn file_path(dir: &Path, kind: &str, number: u64) -> PathBuf {
+    dir.join(format!("{kind}-{number:06}.jsonl"))
+}
+
+fn open_segment(dir: &Path, segment: u64) -> BoxedResult<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path(dir, "segment", segment))?;
+    Ok(file)
+}
+
+// the numbers of all `kind` files in `dir`, sorted
+fn numbered_files(dir: &Path, kind: &str) -> BoxedResult<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(kind)?.strip_prefix('-'))
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|number| number.parse().ok());
+        if let Some(number) = number {
+            numbers.push(number);
+        }
+    }
+    numbers.sort();
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory per test, removed again when the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("chat-store-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+
+        fn files(&self, kind: &str) -> Vec<u64> {
+            numbered_files(&self.0, kind).unwrap()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn user(name: &str) -> Record {
+        Record::KnownUser {
+            name: name.to_string(),
+        }
+    }
+
+    fn names(records: Vec<Record>) -> Vec<String> {
+        records
+            .into_iter()
+            .map(|record| match record {
+                Record::KnownUser { name } => name,
+                _ => panic!("only known users are stored in these tests"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn append_then_load() {
+        let dir = TestDir::new("append");
+        let mut store = JsonLinesStore::open(&dir.0, 1024, 4).unwrap();
+        store.append(&user("alice")).unwrap();
+        store.append(&user("bob")).unwrap();
+        assert_eq!(names(store.load().unwrap()), ["alice", "bob"]);
+
+        let mut reopened = JsonLinesStore::open(&dir.0, 1024, 4).unwrap();
+        assert_eq!(names(reopened.load().unwrap()), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn rotates_at_segment_bytes() {
+        let dir = TestDir::new("rotate");
+        // every record fills a segment on its own
+        let mut store = JsonLinesStore::open(&dir.0, 1, 3).unwrap();
+        store.append(&user("alice")).unwrap();
+        store.append(&user("bob")).unwrap();
+        assert_eq!(dir.files("segment"), [1, 2, 3]);
+        assert!(!store.needs_compaction());
+        store.append(&user("carol")).unwrap();
+        assert!(store.needs_compaction());
+        assert_eq!(names(store.load().unwrap()), ["alice", "bob", "carol"]);
+    }
+
+    #[test]
+    fn compaction_removes_what_it_replaces() {
+        let dir = TestDir::new("compact");
+        let mut store = JsonLinesStore::open(&dir.0, 1, 2).unwrap();
+        store.append(&user("alice")).unwrap();
+        store.append(&user("bob")).unwrap();
+        store.compact(&[user("bob")]).unwrap();
+        assert!(!store.needs_compaction());
+        store.append(&user("carol")).unwrap();
+        store.compact(&[user("bob"), user("carol")]).unwrap();
+        store.append(&user("dave")).unwrap();
+
+        assert_eq!(dir.files("snapshot"), [5]);
+        assert_eq!(dir.files("segment"), [6, 7]);
+        assert_eq!(names(store.load().unwrap()), ["bob", "carol", "dave"]);
+        let mut reopened = JsonLinesStore::open(&dir.0, 1, 2).unwrap();
+        assert_eq!(names(reopened.load().unwrap()), ["bob", "carol", "dave"]);
+    }
+
+    #[test]
+    fn skips_a_cut_off_line() {
+        let dir = TestDir::new("cut-off");
+        let mut store = JsonLinesStore::open(&dir.0, 1024, 4).unwrap();
+        store.append(&user("alice")).unwrap();
+        // the server crashed in the middle of the next record
+        store
+            .file
+            .write_all(br#"{"record":"known_user","na"#)
+            .unwrap();
+        drop(store);
+
+        let mut store = JsonLinesStore::open(&dir.0, 1024, 4).unwrap();
+        assert_eq!(names(store.load().unwrap()), ["alice"]);
+        store.append(&user("bob")).unwrap();
+        assert_eq!(names(store.load().unwrap()), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn open_removes_unfinished_snapshots() {
+        let dir = TestDir::new("tmp");
+        let mut store = JsonLinesStore::open(&dir.0, 1024, 4).unwrap();
+        store.append(&user("alice")).unwrap();
+        drop(store);
+        let temporary = file_path(&dir.0, "snapshot", 1).with_extension("tmp");
+        fs::write(
+            &temporary,
+            "{\"record\":\"known_user\",\"name\":\"mallory\"}\n",
+        )
+        .unwrap();
+
+        let mut store = JsonLinesStore::open(&dir.0, 1024, 4).unwrap();
+        assert!(!temporary.exists());
+        assert_eq!(names(store.load().unwrap()), ["alice"]);
+    }
+
+    #[tokio::test]
+    async fn store_thread_keeps_the_order() {
+        let mut memory = MemoryStore::default();
+        let store = StoreThread::spawn(Box::new(memory.clone()));
+        store.append(user("alice"));
+        store.compact(vec![user("bob")]);
+        store.append(user("carol"));
+        assert_eq!(names(store.load().await.unwrap()), ["bob", "carol"]);
+        store.append(user("dave"));
+        store.close().await;
+        assert_eq!(names(memory.load().unwrap()), ["bob", "carol", "dave"]);
+    }
+
+    #[tokio::test]
+    async fn store_thread_asks_for_compaction_once() {
+        let dir = TestDir::new("thread");
+        let store = JsonLinesStore::open(&dir.0, 1, 2).unwrap();
+        let store = StoreThread::spawn(Box::new(store));
+        store.append(user("alice"));
+        store.append(user("bob"));
+        store.append(user("carol"));
+        // a load waits for everything before it
+        store.load().await.unwrap();
+        assert!(store.needs_compaction());
+        assert!(!store.needs_compaction());
+        store.compact(vec![user("carol")]);
+        store.load().await.unwrap();
+        assert!(!store.needs_compaction());
+        store.close().await;
+    }
+}
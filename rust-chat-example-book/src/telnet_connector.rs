@@ -1,138 +1,184 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    sync::Arc,
+    collections::VecDeque,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
 };
 
+use async_trait::async_trait;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream, ToSocketAddrs},
-    signal::ctrl_c,
-    sync::{
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        Notify,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, ToSocketAddrs,
     },
+    sync::{mpsc::UnboundedSender, Mutex, Notify},
 };
 
-use crate::utils::{spawn_and_log_error, BoxedResult};
+use crate::broker::{
+    client_names, handle_input, join, spawn_and_log_error, Event, Join, Transport,
+};
+use crate::line_editor::LineEditor;
+use crate::message::OutputFormat;
+use crate::telnet_protocol::TelnetProtocol;
+use crate::utils::BoxedResult;
 
-async fn accept_loop(addr: impl ToSocketAddrs) -> BoxedResult<()> {
-    let listener = TcpListener::bind(addr).await?;
-    println!("Connected to {}", listener.local_addr()?.to_string());
+// the reading side answers negotiations and echoes input,
+// so both sides of the connection need to write to the socket
+type SharedWriteHalf = Arc<Mutex<OwnedWriteHalf>>;
 
-    let (broker_sender, broker_receiver) = unbounded_channel();
-    let broker = tokio::spawn(broker_loop(broker_receiver));
+struct TelnetTransport {
+    write_half: SharedWriteHalf,
+    // 0 as long as the client doesn't tell us its window size
+    window_width: Arc<AtomicU16>,
+    // tells the reading side to give up on the client
+    closed: Arc<Notify>,
+    peer_ip: Option<IpAddr>,
+}
 
-    let shutdown_notifaction = Arc::new(Notify::new());
+#[async_trait]
+impl Transport for TelnetTransport {
+    async fn send(&mut self, message: &str, format: OutputFormat) -> BoxedResult<()> {
+        let width = self.window_width.load(Ordering::Relaxed) as usize;
+        let data = telnet_lines(message, format, width);
+        self.write_half
+            .lock()
+            .await
+            .write_all(data.as_bytes())
+            .await?;
+        Ok(())
+    }
 
-    loop {
-        tokio::select! {
-            Ok((stream, _addr)) = listener.accept() => {
-                println!("Client joined...");
+    fn kind(&self) -> &'static str {
+        "telnet"
+    }
 
-                spawn_and_log_error(handle_client_communication(broker_sender.clone(), stream, shutdown_notifaction.clone()));
-            },
-            _ = ctrl_c() => break,
-        }
+    async fn close(&mut self) {
+        self.closed.notify_one();
     }
 
-    println!("Shutting down server...");
-    shutdown_notifaction.notify_waiters();
-    drop(broker_sender);
-    broker.await?;
-    Ok(())
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_ip
+    }
 }
 
-enum Event {
-    NewClient {
-        name: String,
-        write_half: OwnedWriteHalf,
-    },
-    Message {
-        from_name: String,
-        to_names: Vec<String>,
-        message: String,
-    },
+struct TelnetReader {
+    read_half: OwnedReadHalf,
+    write_half: SharedWriteHalf,
+    protocol: TelnetProtocol,
+    window_width: Arc<AtomicU16>,
+    closed: Arc<Notify>,
+    editor: LineEditor,
+    lines: VecDeque<String>,
+    // asked for the names of other clients on tab
+    broker_sender: UnboundedSender<Event>,
 }
 
-async fn broker_loop(mut events: UnboundedReceiver<Event>) {
-    let mut clients: HashMap<String, UnboundedSender<String>> = HashMap::new();
+impl TelnetReader {
+    async fn new(
+        read_half: OwnedReadHalf,
+        write_half: SharedWriteHalf,
+        broker_sender: UnboundedSender<Event>,
+    ) -> BoxedResult<Self> {
+        let mut protocol = TelnetProtocol::new();
+        write_half
+            .lock()
+            .await
+            .write_all(&protocol.initial_negotiation())
+            .await?;
 
-    loop {
-        let event = match events.recv().await {
-            Some(event) => event,
-            None => break,
-        };
+        Ok(TelnetReader {
+            read_half,
+            write_half,
+            protocol,
+            window_width: Arc::new(AtomicU16::new(0)),
+            closed: Arc::new(Notify::new()),
+            editor: LineEditor::new(),
+            lines: VecDeque::new(),
+            broker_sender,
+        })
+    }
 
-        match event {
-            Event::NewClient {
-                name,
-                mut write_half,
-            } => match clients.entry(name.clone()) {
-                Entry::Occupied(_) => (),
-                Entry::Vacant(entry) => {
-                    let (client_sender, mut client_receiver) = unbounded_channel();
-                    entry.insert(client_sender);
-                    spawn_and_log_error(async move {
-                        receive_messages_on_loop(&mut client_receiver, &mut write_half).await
-                    });
-                }
-            },
-            Event::Message {
-                from_name: from,
-                to_names: to,
-                message: msg,
-            } => {
-                let all_command = "all".to_string();
-
-                if to.contains(&all_command) {
-                    for client in &clients {
-                        if client.0 != &from {
-                            let _ = send_message(&from, &all_command, &msg, client.1).await;
-                        }
-                    }
-                } else {
-                    for addr in to {
-                        if let Some(client) = clients.get(&addr) {
-                            let _ = send_message(&from, &addr, &msg, client).await;
-                        }
-                    }
-                }
-            }
+    fn transport(&self) -> TelnetTransport {
+        TelnetTransport {
+            write_half: self.write_half.clone(),
+            window_width: self.window_width.clone(),
+            closed: self.closed.clone(),
+            peer_ip: self.read_half.peer_addr().ok().map(|addr| addr.ip()),
         }
     }
-    for client in &clients {
-        let sending_attempt = client
-            .1
-            .send("Admin is shutting down the server...".to_string());
-        if let Err(e) = sending_attempt {
-            eprintln!(
-                "Error while sending shutdown message on {:?}: {e}",
-                &client.0
-            );
+
+    async fn write(&self, data: &[u8]) -> BoxedResult<()> {
+        if !data.is_empty() {
+            self.write_half.lock().await.write_all(data).await?;
         }
+        Ok(())
     }
-    drop(clients);
-}
 
-async fn send_message(from: &str, to: &str, msg: &str, client: &UnboundedSender<String>) {
-    let message = format!("{:?}: {:?}\n", from, &msg);
-    let sending_attempt = client.send(message.clone());
-    if let Err(e) = sending_attempt {
-        eprintln!("Error while receiving message from {from} on {:?}: {e}", to);
+    // returns None once the client closed the connection
+    async fn next_line(&mut self) -> BoxedResult<Option<String>> {
+        let mut buffer = [0; 1024];
+
+        loop {
+            if let Some(line) = self.lines.pop_front() {
+                return Ok(Some(line));
+            }
+
+            let read = self.read_half.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+
+            let received = self.protocol.receive(&buffer[..read]);
+            self.write(&received.reply).await?;
+            // 0 once the client no longer tells us its size, so nothing is wrapped anymore
+            let width = self.protocol.window_size().map_or(0, |(width, _)| width);
+            self.window_width.store(width, Ordering::Relaxed);
+
+            let names = match received.data.contains(&b'\t') {
+                true => client_names(&self.broker_sender).await,
+                false => Vec::new(),
+            };
+            let edited = self.editor.feed(&received.data, &names);
+            if self.protocol.server_echoes() {
+                self.write(&edited.echo).await?;
+            }
+            self.lines.extend(edited.lines);
+        }
     }
 }
 
-async fn receive_messages_on_loop(
-    client_receiver: &mut UnboundedReceiver<String>,
-    write_half: &mut OwnedWriteHalf,
+// the broker and the notifications are owned by the caller,
+// so telnet and ssh clients end up in the same chat and stop together:
+// `stop_accepting` turns new clients away, `shutdown_notification` closes the connections
+pub async fn accept_loop(
+    addr: impl ToSocketAddrs,
+    broker_sender: UnboundedSender<Event>,
+    stop_accepting: Arc<Notify>,
+    shutdown_notification: Arc<Notify>,
+    motd: Arc<str>,
 ) -> BoxedResult<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("Telnet server listening on {}", listener.local_addr()?);
+
+    // created before the loop, so a notification between two accepts is not missed
+    let stop = stop_accepting.notified();
+    tokio::pin!(stop);
+
     loop {
-        let message = client_receiver.recv().await;
-        match message {
-            Some(message) => write_half.write_all(&message.as_bytes()).await?,
-            None => break,
+        tokio::select! {
+            Ok((stream, _addr)) = listener.accept() => {
+                println!("Client joined...");
+
+                spawn_and_log_error(handle_client_communication(broker_sender.clone(), stream, shutdown_notification.clone(), motd.clone()));
+            },
+            _ = &mut stop => break,
         }
     }
+
+    println!("Telnet server stopped.");
     Ok(())
 }
 
@@ -140,55 +186,148 @@ async fn handle_client_communication(
     broker_sender: UnboundedSender<Event>,
     stream: TcpStream,
     shutdown_notification: Arc<Notify>,
+    motd: Arc<str>,
 ) -> BoxedResult<()> {
-    let (read_half, mut write_half) = stream.into_split();
-    let reader = BufReader::new(read_half);
-    let mut lines = reader.lines();
-
-    write_half
-        .write_all(&"Input your name: ".as_bytes())
-        .await
-        .unwrap();
-
-    // lines.next_line() should be cleaned up, to avoid f.e. backspace appearing like "arai\u{8}\u{8}iana"
-    // also, \u{1b}[Auser2
-    let name = match lines.next_line().await? {
-        None => "_".to_string(),
-        Some(line) => line,
+    let (read_half, write_half) = stream.into_split();
+    let mut lines = TelnetReader::new(
+        read_half,
+        Arc::new(Mutex::new(write_half)),
+        broker_sender.clone(),
+    )
+    .await?;
+    lines
+        .write(format!("{}\r\n", motd.replace('\n', "\r\n")).as_bytes())
+        .await?;
+
+    // ask until the broker accepts the name
+    let name = loop {
+        lines.write(b"Input your name: ").await?;
+        let name = match lines.next_line().await? {
+            None => return Ok(()),
+            Some(line) => line,
+        };
+        match join(&broker_sender, &name, Box::new(lines.transport())).await? {
+            Join::Accepted { name } => break name,
+            Join::Rejected { reason } => lines.write(format!("{reason}\r\n").as_bytes()).await?,
+        }
     };
     println!("{} joined.", name);
 
-    broker_sender
-        .send(Event::NewClient {
-            name: name.clone(),
-            write_half,
-        })
-        .unwrap();
-
+    let closed = lines.closed.clone();
     loop {
         tokio::select! {
-            Ok(Some(line)) = lines.next_line() => {
-                println!("{:?}", &line);
-                let (dest, message) = match line.find(':') {
-                    None => continue,
-                    Some(idx) => (&line[..idx], line[idx + 1..].trim()),
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    // the client closed the connection
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("Error while reading from {:?}: {e}", name);
+                        break;
+                    }
                 };
-                let dest: Vec<String> = dest
-                    .split(',')
-                    .map(|name| name.trim().to_string())
-                    .collect();
-                let message = message.trim().to_string();
-
-                broker_sender
-                    .send(Event::Message {
-                        from_name: name.clone(),
-                        to_names: dest,
-                        message,
-                    })
-                    .unwrap();
+                println!("{:?}", &line);
+                if !handle_input(&broker_sender, &name, &line)? {
+                    break;
+                }
             },
-            _ = shutdown_notification.notified() => break,
+            _ = closed.notified() => break,
+            // the broker says goodbye to everyone itself
+            _ = shutdown_notification.notified() => return Ok(()),
         }
     }
+
+    broker_sender.send(Event::Disconnect { name })?;
     Ok(())
 }
+
+// text is wrapped to the client's window, json has to stay on one line however long it is
+fn telnet_lines(message: &str, format: OutputFormat, width: usize) -> String {
+    match format {
+        OutputFormat::Text => format!("{}\r\n", wrap_to_width(message, width).join("\r\n")),
+        OutputFormat::Json => format!("{message}\r\n"),
+    }
+}
+
+// splits every line of `message` into lines of at most `width` characters,
+// breaking at spaces where possible
+fn wrap_to_width(message: &str, width: usize) -> Vec<String> {
+    if width == 0 {
+        return message.split('\n').map(str::to_string).collect();
+    }
+
+    let mut wrapped = Vec::new();
+
+    for line in message.split('\n') {
+        let mut current = String::new();
+        let mut current_width = 0;
+
+        for word in line.split(' ') {
+            let mut word: Vec<char> = word.chars().collect();
+
+            if current_width > 0 && current_width + 1 + word.len() > width {
+                wrapped.push(std::mem::take(&mut current));
+                current_width = 0;
+            }
+            if current_width > 0 {
+                current.push(' ');
+                current_width += 1;
+            }
+            // words that don't fit on a line at all are cut into pieces
+            while current_width + word.len() > width {
+                let rest = word.split_off(width - current_width);
+                current.extend(word);
+                wrapped.push(std::mem::take(&mut current));
+                current_width = 0;
+                word = rest;
+            }
+            current_width += word.len();
+            current.extend(word);
+        }
+        wrapped.push(current);
+    }
+
+    wrapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_text_is_wrapped() {
+        let message = "the quick brown fox";
+        assert_eq!(
+            telnet_lines(message, OutputFormat::Text, 10),
+            "the quick\r\nbrown fox\r\n"
+        );
+        assert_eq!(
+            telnet_lines(message, OutputFormat::Json, 10),
+            "the quick brown fox\r\n"
+        );
+    }
+
+    #[test]
+    fn wrapping_breaks_at_spaces() {
+        assert_eq!(
+            wrap_to_width("the quick brown fox", 10),
+            vec!["the quick", "brown fox"]
+        );
+    }
+
+    #[test]
+    fn wrapping_cuts_long_words() {
+        assert_eq!(wrap_to_width("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn wrapping_keeps_line_breaks() {
+        assert_eq!(wrap_to_width("a b\nc", 80), vec!["a b", "c"]);
+        assert_eq!(wrap_to_width("a b\nc", 0), vec!["a b", "c"]);
+    }
+
+    #[test]
+    fn wrapping_counts_characters_not_bytes() {
+        assert_eq!(wrap_to_width("äöü äöü", 3), vec!["äöü", "äöü"]);
+    }
+}
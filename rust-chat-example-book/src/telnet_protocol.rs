@@ -0,0 +1,383 @@
+// Telnet sends its own commands in between the bytes the user types.
+// Every command starts with IAC ("interpret as command"), f.e.
+//      IAC WILL ECHO                       -> "I will echo what you type"
+//      IAC SB NAWS 0 80 0 24 IAC SE        -> "my window is 80x24"
+// TelnetProtocol strips these out of the input, answers the negotiations
+// and remembers the window size the client told us about.
+
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+pub const SB: u8 = 250;
+pub const SE: u8 = 240;
+
+pub const ECHO: u8 = 1;
+pub const SUPPRESS_GO_AHEAD: u8 = 3;
+pub const NAWS: u8 = 31;
+
+// NAWS needs 5 bytes, anything much longer is nothing we understand and only takes memory
+const MAX_SUBNEGOTIATION_LENGTH: usize = 64;
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum OptionState {
+    Off,
+    // we asked for it and wait for the client to agree
+    Requested,
+    On,
+}
+
+#[derive(Clone, Copy)]
+enum ParserState {
+    Data,
+    Cr,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+pub struct TelnetProtocol {
+    state: ParserState,
+    subnegotiation: Vec<u8>,
+    // options the server does (ECHO, SUPPRESS_GO_AHEAD)
+    local_echo: OptionState,
+    local_suppress_go_ahead: OptionState,
+    // options the client does (SUPPRESS_GO_AHEAD, NAWS)
+    remote_suppress_go_ahead: OptionState,
+    remote_naws: OptionState,
+    window_size: Option<(u16, u16)>,
+}
+
+// what came out of one chunk of bytes read from the socket
+#[derive(Default)]
+pub struct Received {
+    // user input, with every line ending turned into a single '\n'
+    pub data: Vec<u8>,
+    // negotiation answers that have to be written back to the client
+    pub reply: Vec<u8>,
+}
+
+impl TelnetProtocol {
+    pub fn new() -> Self {
+        TelnetProtocol {
+            state: ParserState::Data,
+            subnegotiation: Vec::new(),
+            local_echo: OptionState::Off,
+            local_suppress_go_ahead: OptionState::Off,
+            remote_suppress_go_ahead: OptionState::Off,
+            remote_naws: OptionState::Off,
+            window_size: None,
+        }
+    }
+
+    // sent once after connecting: the server echoes and doesn't wait for go-aheads
+    // (which puts the client into character mode), and the client should tell us its window size
+    pub fn initial_negotiation(&mut self) -> Vec<u8> {
+        self.local_echo = OptionState::Requested;
+        self.local_suppress_go_ahead = OptionState::Requested;
+        self.remote_suppress_go_ahead = OptionState::Requested;
+        self.remote_naws = OptionState::Requested;
+        vec![
+            IAC,
+            WILL,
+            ECHO,
+            IAC,
+            WILL,
+            SUPPRESS_GO_AHEAD,
+            IAC,
+            DO,
+            SUPPRESS_GO_AHEAD,
+            IAC,
+            DO,
+            NAWS,
+        ]
+    }
+
+    // (width, height) in characters, if the client sent it with NAWS
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.window_size
+    }
+
+    // true when the client left echoing to us, so typed characters have to be sent back
+    pub fn server_echoes(&self) -> bool {
+        self.local_echo == OptionState::On
+    }
+
+    pub fn receive(&mut self, bytes: &[u8]) -> Received {
+        let mut received = Received::default();
+
+        for &byte in bytes {
+            self.state = match self.state {
+                ParserState::Data => match byte {
+                    IAC => ParserState::Iac,
+                    CR => ParserState::Cr,
+                    _ => {
+                        received.data.push(byte);
+                        ParserState::Data
+                    }
+                },
+                // a line ending is CR LF or CR NUL, some clients only send CR
+                ParserState::Cr => {
+                    received.data.push(LF);
+                    match byte {
+                        LF | NUL => ParserState::Data,
+                        IAC => ParserState::Iac,
+                        CR => ParserState::Cr,
+                        _ => {
+                            received.data.push(byte);
+                            ParserState::Data
+                        }
+                    }
+                }
+                ParserState::Iac => match byte {
+                    // IAC IAC is an escaped 255 data byte
+                    IAC => {
+                        received.data.push(IAC);
+                        ParserState::Data
+                    }
+                    WILL | WONT | DO | DONT => ParserState::Negotiation(byte),
+                    SB => {
+                        self.subnegotiation.clear();
+                        ParserState::Subnegotiation
+                    }
+                    // NOP, GA, AYT and friends carry no data for the chat
+                    _ => ParserState::Data,
+                },
+                ParserState::Negotiation(command) => {
+                    self.negotiate(command, byte, &mut received.reply);
+                    ParserState::Data
+                }
+                ParserState::Subnegotiation => match byte {
+                    IAC => ParserState::SubnegotiationIac,
+                    _ => self.push_subnegotiation(byte),
+                },
+                ParserState::SubnegotiationIac => match byte {
+                    SE => {
+                        self.subnegotiate();
+                        ParserState::Data
+                    }
+                    IAC => self.push_subnegotiation(IAC),
+                    _ => ParserState::Subnegotiation,
+                },
+            };
+        }
+
+        received
+    }
+
+    // Answers are only sent when an option actually changes,
+    // otherwise client and server would keep confirming each other forever.
+    fn negotiate(&mut self, command: u8, option: u8, reply: &mut Vec<u8>) {
+        match command {
+            DO => match self.local_option(option) {
+                Some(state) => {
+                    if *state == OptionState::Off {
+                        reply.extend([IAC, WILL, option]);
+                    }
+                    *state = OptionState::On;
+                }
+                None => reply.extend([IAC, WONT, option]),
+            },
+            DONT => {
+                if let Some(state) = self.local_option(option) {
+                    if *state == OptionState::On {
+                        reply.extend([IAC, WONT, option]);
+                    }
+                    *state = OptionState::Off;
+                }
+            }
+            WILL => match self.remote_option(option) {
+                Some(state) => {
+                    if *state == OptionState::Off {
+                        reply.extend([IAC, DO, option]);
+                    }
+                    *state = OptionState::On;
+                }
+                None => reply.extend([IAC, DONT, option]),
+            },
+            WONT => {
+                if let Some(state) = self.remote_option(option) {
+                    if *state == OptionState::On {
+                        reply.extend([IAC, DONT, option]);
+                    }
+                    *state = OptionState::Off;
+                }
+                if option == NAWS {
+                    self.window_size = None;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn local_option(&mut self, option: u8) -> Option<&mut OptionState> {
+        match option {
+            ECHO => Some(&mut self.local_echo),
+            SUPPRESS_GO_AHEAD => Some(&mut self.local_suppress_go_ahead),
+            _ => None,
+        }
+    }
+
+    fn remote_option(&mut self, option: u8) -> Option<&mut OptionState> {
+        match option {
+            SUPPRESS_GO_AHEAD => Some(&mut self.remote_suppress_go_ahead),
+            NAWS => Some(&mut self.remote_naws),
+            _ => None,
+        }
+    }
+
+    // gives up on subnegotiations that are too long, what follows is read as data again
+    fn push_subnegotiation(&mut self, byte: u8) -> ParserState {
+        if self.subnegotiation.len() >= MAX_SUBNEGOTIATION_LENGTH {
+            self.subnegotiation.clear();
+            return ParserState::Data;
+        }
+        self.subnegotiation.push(byte);
+        ParserState::Subnegotiation
+    }
+
+    fn subnegotiate(&mut self) {
+        if let [NAWS, width_high, width_low, height_high, height_low] = self.subnegotiation[..] {
+            let width = u16::from_be_bytes([width_high, width_low]);
+            let height = u16::from_be_bytes([height_high, height_low]);
+            // 0 means the client doesn't know its size
+            self.window_size = match (width, height) {
+                (0, _) | (_, 0) => None,
+                size => Some(size),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a client that agreed to everything from `initial_negotiation`
+    fn negotiated() -> TelnetProtocol {
+        let mut protocol = TelnetProtocol::new();
+        protocol.initial_negotiation();
+        protocol.receive(&[IAC, DO, ECHO, IAC, DO, SUPPRESS_GO_AHEAD]);
+        protocol.receive(&[IAC, WILL, SUPPRESS_GO_AHEAD, IAC, WILL, NAWS]);
+        protocol
+    }
+
+    #[test]
+    fn plain_data_passes_through() {
+        let received = TelnetProtocol::new().receive(b"hello");
+        assert_eq!(received.data, b"hello");
+        assert!(received.reply.is_empty());
+    }
+
+    #[test]
+    fn line_endings_become_lf() {
+        let mut protocol = TelnetProtocol::new();
+        assert_eq!(protocol.receive(b"a\r\nb\r\0c\rd").data, b"a\nb\nc\nd");
+    }
+
+    #[test]
+    fn cr_lf_split_across_reads() {
+        let mut protocol = TelnetProtocol::new();
+        assert_eq!(protocol.receive(b"a\r").data, b"a");
+        assert_eq!(protocol.receive(b"\nb").data, b"\nb");
+    }
+
+    #[test]
+    fn escaped_iac_is_data() {
+        let received = TelnetProtocol::new().receive(&[b'a', IAC, IAC, b'b']);
+        assert_eq!(received.data, [b'a', IAC, b'b']);
+    }
+
+    #[test]
+    fn commands_are_stripped_from_data() {
+        // IAC NOP and a negotiation in the middle of the input
+        let received =
+            TelnetProtocol::new().receive(&[b'a', IAC, 241, b'b', IAC, DONT, ECHO, b'c']);
+        assert_eq!(received.data, b"abc");
+    }
+
+    #[test]
+    fn agreeing_to_our_requests_needs_no_answer() {
+        let mut protocol = TelnetProtocol::new();
+        protocol.initial_negotiation();
+        let received = protocol.receive(&[IAC, DO, ECHO, IAC, WILL, NAWS]);
+        assert!(received.reply.is_empty());
+        assert!(protocol.server_echoes());
+    }
+
+    #[test]
+    fn unknown_options_are_refused() {
+        let received = TelnetProtocol::new().receive(&[IAC, DO, 24, IAC, WILL, 24]);
+        assert_eq!(received.reply, [IAC, WONT, 24, IAC, DONT, 24]);
+    }
+
+    #[test]
+    fn options_asked_for_by_the_client_are_confirmed_once() {
+        let mut protocol = TelnetProtocol::new();
+        assert_eq!(protocol.receive(&[IAC, DO, ECHO]).reply, [IAC, WILL, ECHO]);
+        assert!(protocol.receive(&[IAC, DO, ECHO]).reply.is_empty());
+        assert_eq!(
+            protocol.receive(&[IAC, DONT, ECHO]).reply,
+            [IAC, WONT, ECHO]
+        );
+        assert!(!protocol.server_echoes());
+    }
+
+    #[test]
+    fn naws_sets_the_window_size() {
+        let mut protocol = negotiated();
+        let received = protocol.receive(&[IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE, b'x']);
+        assert_eq!(received.data, b"x");
+        assert_eq!(protocol.window_size(), Some((80, 24)));
+    }
+
+    #[test]
+    fn naws_split_across_reads() {
+        let mut protocol = negotiated();
+        protocol.receive(&[IAC, SB, NAWS, 1]);
+        assert_eq!(protocol.window_size(), None);
+        protocol.receive(&[44, 0, 50, IAC]);
+        protocol.receive(&[SE]);
+        assert_eq!(protocol.window_size(), Some((300, 50)));
+    }
+
+    #[test]
+    fn naws_with_escaped_255() {
+        let mut protocol = negotiated();
+        protocol.receive(&[IAC, SB, NAWS, 0, IAC, IAC, 0, 24, IAC, SE]);
+        assert_eq!(protocol.window_size(), Some((255, 24)));
+    }
+
+    #[test]
+    fn unknown_window_size_is_ignored() {
+        let mut protocol = negotiated();
+        protocol.receive(&[IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE]);
+        protocol.receive(&[IAC, SB, NAWS, 0, 0, 0, 0, IAC, SE]);
+        assert_eq!(protocol.window_size(), None);
+    }
+
+    #[test]
+    fn endless_subnegotiation_is_dropped() {
+        let mut protocol = negotiated();
+        protocol.receive(&[IAC, SB, NAWS]);
+        protocol.receive(&[0; 1000]);
+        assert!(protocol.subnegotiation.len() <= MAX_SUBNEGOTIATION_LENGTH);
+        assert_eq!(protocol.receive(b"hi").data, b"hi");
+        assert_eq!(protocol.window_size(), None);
+    }
+
+    #[test]
+    fn wont_naws_forgets_the_window_size() {
+        let mut protocol = negotiated();
+        protocol.receive(&[IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE]);
+        let received = protocol.receive(&[IAC, WONT, NAWS]);
+        assert_eq!(received.reply, [IAC, DONT, NAWS]);
+        assert_eq!(protocol.window_size(), None);
+    }
+}
@@ -1,81 +1,420 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    env, fs,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{ReadHalf, WriteHalf},
-        TcpListener,
-    },
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::TcpListener,
+    signal::ctrl_c,
     sync::broadcast::{self, error::RecvError, Receiver, Sender},
+    task::JoinSet,
 };
 
+// start with cargo run, then connect from other terminals with:    telnet localhost 8080
+// CHAT_ADDRESS and CHAT_CAPACITY change where the server listens
+// and how many messages a slow client may fall behind before it misses some,
+// CHAT_MOTD names a text file that greets everyone who connects
+// everyone picks a nickname first, after that every line goes to everyone else,
+// except for the commands /nick <name>, /who and /quit
+
+const DEFAULT_ADDRESS: &str = "localhost:8080";
+const DEFAULT_CAPACITY: usize = 10;
+// how long clients get to receive the goodbye after ctrl + c
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(2);
+const MAX_NAME_LENGTH: usize = 20;
+const DEFAULT_MOTD: &str = "Welcome!";
+
+#[derive(Clone)]
+enum Message {
+    Chat {
+        text: String,
+        name: String,
+        from: SocketAddr,
+    },
+    // f.e. "bob joined", for everyone but `from`
+    Announcement {
+        text: String,
+        from: SocketAddr,
+    },
+    Shutdown,
+}
+
+// what all clients share
+#[derive(Clone)]
+struct Room {
+    tx: Sender<Message>,
+    names: Arc<Mutex<HashMap<SocketAddr, String>>>,
+    motd: Arc<str>,
+}
+
+impl Room {
+    // takes `name` for the client at `addr`, or explains why it can't have it
+    fn claim_name(&self, addr: SocketAddr, name: &str) -> Result<(), String> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err("A name can't be empty or contain spaces.".to_string());
+        }
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!(
+                "A name can have at most {MAX_NAME_LENGTH} characters."
+            ));
+        }
+
+        let mut names = self.names.lock().unwrap();
+        if names
+            .iter()
+            .any(|(other, other_name)| other_name == name && *other != addr)
+        {
+            return Err(format!("{name} is already taken."));
+        }
+        names.insert(addr, name.to_string());
+        Ok(())
+    }
+
+    fn release_name(&self, addr: &SocketAddr) {
+        self.names.lock().unwrap().remove(addr);
+    }
+
+    fn who(&self) -> String {
+        let names = self.names.lock().unwrap();
+        let mut names: Vec<&str> = names.values().map(|name| name.as_str()).collect();
+        names.sort();
+        format!("Online: {}", names.join(", "))
+    }
+
+    // sending only fails without receivers, and then there is nobody to tell anyway
+    fn announce(&self, from: SocketAddr, text: String) {
+        let _ = self.tx.send(Message::Announcement { text, from });
+    }
+
+    fn chat(&self, from: SocketAddr, name: &str, text: String) {
+        let _ = self.tx.send(Message::Chat {
+            text,
+            name: name.to_string(),
+            from,
+        });
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("localhost:8080").await.unwrap();
+    let address = env::var("CHAT_ADDRESS").unwrap_or(DEFAULT_ADDRESS.to_string());
+    let capacity = match env::var("CHAT_CAPACITY") {
+        Ok(capacity) => match capacity.parse() {
+            Ok(capacity) if capacity > 0 => capacity,
+            _ => panic!("CHAT_CAPACITY must be a number greater than 0."),
+        },
+        Err(_) => DEFAULT_CAPACITY,
+    };
+    let motd = match env::var("CHAT_MOTD") {
+        Ok(path) => match fs::read_to_string(&path) {
+            Ok(motd) => motd.trim_end().into(),
+            Err(e) => panic!("CHAT_MOTD {path:?} could not be read: {e}"),
+        },
+        Err(_) => DEFAULT_MOTD.into(),
+    };
+
+    let listener = TcpListener::bind(&address)
+        .await
+        .unwrap_or_else(|e| panic!("Could not listen on {address}: {e}"));
 
-    let (tx, _rx) = broadcast::channel(10);
-    println!("Connected to localhost:8080");
+    let (tx, _rx) = broadcast::channel(capacity);
+    let room = Room {
+        tx,
+        names: Arc::new(Mutex::new(HashMap::new())),
+        motd,
+    };
+    println!("Connected to {address}");
 
+    let mut clients = JoinSet::new();
     loop {
-        handle_client(&listener, tx.clone()).await;
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, addr)) => {
+                    clients.spawn(handle_client(socket, addr, room.clone()));
+                }
+                Err(e) => eprintln!("Could not accept a client: {e}"),
+            },
+            // forget about clients that left
+            Some(_) = clients.join_next() => (),
+            _ = ctrl_c() => break,
+        }
+    }
+
+    println!("Shutting down...");
+    let _ = room.tx.send(Message::Shutdown);
+    let all_left = tokio::time::timeout(SHUTDOWN_DEADLINE, async {
+        while clients.join_next().await.is_some() {}
+    })
+    .await;
+    if all_left.is_err() {
+        eprintln!("Not every client got the goodbye in time.");
     }
 }
 
-async fn handle_client(listener: &TcpListener, tx: Sender<(String, SocketAddr)>) {
-    let mut rx = tx.subscribe();
-    let (mut socket, addr) = listener.accept().await.unwrap();
+// `socket` is a TcpStream, or anything else that can stand in for one in the tests
+async fn handle_client(socket: impl AsyncRead + AsyncWrite, addr: SocketAddr, room: Room) {
     println!("Client joined...");
 
-    tokio::spawn(async move {
-        let (read_half, mut write_half) = socket.split();
-        let mut reader = BufReader::new(read_half);
+    let (read_half, mut write_half) = tokio::io::split(socket);
+    let mut lines = BufReader::new(read_half).lines();
 
-        let mut line = String::new();
-        write_half.write_all(&"Welcome!".as_bytes()).await.unwrap();
+    let mut name = match ask_for_name(&mut write_half, &mut lines, &room, &addr).await {
+        Ok(Some(name)) => name,
+        Ok(None) => {
+            println!("Client {addr} left before picking a name...");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Lost the connection to {addr}: {e}");
+            return;
+        }
+    };
+    // subscribed only now, so messages don't pile up while the client picks its name
+    let mut rx = room.tx.subscribe();
+    room.announce(addr, format!("{name} joined."));
 
-        loop {
-            handle_communication(
-                &mut write_half,
-                &mut reader,
-                &mut line,
-                tx.clone(),
-                &mut rx,
-                &addr,
-            )
-            .await;
-        }
-    });
+    loop {
+        match handle_communication(
+            &mut write_half,
+            &mut lines,
+            &room,
+            &mut rx,
+            &addr,
+            &mut name,
+        )
+        .await
+        {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("Lost the connection to {addr}: {e}");
+                break;
+            }
+        }
+    }
+
+    room.release_name(&addr);
+    room.announce(addr, format!("{name} left."));
+    println!("Client {addr} ({name}) left...");
 }
 
+// returns None if the client left without picking one
+async fn ask_for_name(
+    write_half: &mut (impl AsyncWrite + Unpin),
+    lines: &mut Lines<impl AsyncBufRead + Unpin>,
+    room: &Room,
+    addr: &SocketAddr,
+) -> std::io::Result<Option<String>> {
+    let welcome = format!("{}\nPick a nickname: ", room.motd);
+    write_half.write_all(welcome.as_bytes()).await?;
+    while let Some(line) = lines.next_line().await? {
+        let name = line.trim();
+        match room.claim_name(*addr, name) {
+            Ok(()) => {
+                let greeting = format!("Hi {name}! Type /who to see who is here.\n");
+                write_half.write_all(greeting.as_bytes()).await?;
+                return Ok(Some(name.to_string()));
+            }
+            Err(reason) => {
+                let retry = format!("{reason} Pick another one: ");
+                write_half.write_all(retry.as_bytes()).await?;
+            }
+        }
+    }
+    Ok(None)
+}
+
+// returns false once the client is gone or the server shuts down
 async fn handle_communication(
-    write_half: &mut WriteHalf<'_>,
-    reader: &mut BufReader<ReadHalf<'_>>,
-    line: &mut String,
-    tx: Sender<(String, SocketAddr)>,
-    rx: &mut Receiver<(String, SocketAddr)>,
+    write_half: &mut (impl AsyncWrite + Unpin),
+    lines: &mut Lines<impl AsyncBufRead + Unpin>,
+    room: &Room,
+    rx: &mut Receiver<Message>,
     addr: &SocketAddr,
-) {
+    name: &mut String,
+) -> std::io::Result<bool> {
     tokio::select! {
-        _result = reader.read_line(line) => {
-            tx.send((line.clone(), addr.clone())).unwrap();
-            line.clear();
+        line = lines.next_line() => match line? {
+            Some(line) => handle_line(line, write_half, room, addr, name).await,
+            // the client closed the connection
+            None => Ok(false),
+        },
+        result = rx.recv() => receive_message(result, write_half, addr).await,
+    }
+}
+
+async fn handle_line(
+    line: String,
+    write_half: &mut (impl AsyncWrite + Unpin),
+    room: &Room,
+    addr: &SocketAddr,
+    name: &mut String,
+) -> std::io::Result<bool> {
+    let reply = match line.trim().split_once(' ') {
+        // nothing to send, the client only pressed enter
+        _ if line.trim().is_empty() => return Ok(true),
+        _ if !line.starts_with('/') => {
+            room.chat(*addr, name, line);
+            return Ok(true);
         }
-        result = rx.recv() => {
-            receive_message(result, write_half, line, addr).await
+        Some(("/nick", new_name)) => match room.claim_name(*addr, new_name.trim()) {
+            Ok(()) => {
+                let new_name = new_name.trim().to_string();
+                room.announce(*addr, format!("{name} is now known as {new_name}."));
+                *name = new_name;
+                format!("You are now known as {name}.")
+            }
+            Err(reason) => reason,
+        },
+        None if line.trim() == "/who" => room.who(),
+        None if line.trim() == "/quit" => {
+            write_half.write_all(b"Bye!\n").await?;
+            return Ok(false);
         }
-    }
+        _ => "The commands are /nick <name>, /who and /quit.".to_string(),
+    };
+    write_half
+        .write_all(format!("{reply}\n").as_bytes())
+        .await?;
+    Ok(true)
 }
 
 async fn receive_message(
-    result: Result<(String, SocketAddr), RecvError>,
-    write_half: &mut WriteHalf<'_>,
-    line: &mut String,
+    result: Result<Message, RecvError>,
+    write_half: &mut (impl AsyncWrite + Unpin),
     addr: &SocketAddr,
-) {
-    let (msg, other_addr) = result.unwrap();
+) -> std::io::Result<bool> {
+    match result {
+        Ok(Message::Chat { text, name, from }) => {
+            if addr != &from {
+                write_half
+                    .write_all(format!("{name}: {text}\n").as_bytes())
+                    .await?;
+            }
+            Ok(true)
+        }
+        Ok(Message::Announcement { text, from }) => {
+            if addr != &from {
+                write_half
+                    .write_all(format!("* {text}\n").as_bytes())
+                    .await?;
+            }
+            Ok(true)
+        }
+        Ok(Message::Shutdown) | Err(RecvError::Closed) => {
+            write_half
+                .write_all(b"The server is shutting down, goodbye!\n")
+                .await?;
+            Ok(false)
+        }
+        // the client read slower than the others wrote
+        Err(RecvError::Lagged(missed)) => {
+            write_half
+                .write_all(format!("You missed {missed} messages.\n").as_bytes())
+                .await?;
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+
+    use super::*;
+
+    fn room(capacity: usize) -> Room {
+        let (tx, _rx) = broadcast::channel(capacity);
+        Room {
+            tx,
+            names: Arc::new(Mutex::new(HashMap::new())),
+            motd: DEFAULT_MOTD.into(),
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    // waits until the room hears `expected`, skipping everything else
+    async fn announced(rx: &mut Receiver<Message>, expected: &str) {
+        loop {
+            if let Message::Announcement { text, .. } = rx.recv().await.unwrap() {
+                if text == expected {
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn read_all(mut client: DuplexStream) -> String {
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn client_closing_the_connection_leaves() {
+        let room = room(10);
+        let mut rx = room.tx.subscribe();
+        let (mut client, server) = duplex(1024);
+        let session = tokio::spawn(handle_client(server, addr(1), room.clone()));
+
+        client.write_all(b"bob\n\n  \nhi\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        session.await.unwrap();
+
+        announced(&mut rx, "bob joined.").await;
+        // the blank lines weren't sent
+        assert!(matches!(rx.recv().await.unwrap(), Message::Chat { text, .. } if text == "hi"));
+        announced(&mut rx, "bob left.").await;
+        assert_eq!(room.who(), "Online: ");
+        assert!(read_all(client).await.contains("Hi bob!"));
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_told_what_it_missed() {
+        let room = room(1);
+        let mut rx = room.tx.subscribe();
+        for text in ["one", "two", "three"] {
+            room.chat(addr(2), "alice", text.to_string());
+        }
+        // the client sends nothing, so only the room has something to say
+        let (client, server) = duplex(1024);
+        let mut lines = BufReader::new(server).lines();
+        let mut output = Vec::new();
+        let mut name = "bob".to_string();
+        for _ in 0..2 {
+            let more =
+                handle_communication(&mut output, &mut lines, &room, &mut rx, &addr(1), &mut name)
+                    .await;
+            assert!(more.unwrap());
+        }
+        drop(client);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "You missed 2 messages.\nalice: three\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_says_goodbye() {
+        let room = room(10);
+        let mut rx = room.tx.subscribe();
+        let (mut client, server) = duplex(1024);
+        let session = tokio::spawn(handle_client(server, addr(1), room.clone()));
+
+        client.write_all(b"bob\n").await.unwrap();
+        // announced only once bob listens to the room
+        announced(&mut rx, "bob joined.").await;
+        assert!(room.tx.send(Message::Shutdown).is_ok());
+        session.await.unwrap();
 
-    if addr != &other_addr {
-        write_half.write_all(&msg.as_bytes()).await.unwrap();
+        let output = read_all(client).await;
+        assert!(output.ends_with("The server is shutting down, goodbye!\n"));
     }
-    line.clear();
 }